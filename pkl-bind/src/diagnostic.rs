@@ -0,0 +1,367 @@
+//! Structured view of the error text reported by pkl
+//!
+//! pkl reports evaluation failures as a preformatted block of text:
+//!
+//! ```text
+//! –– Pkl Error ––
+//! Cannot find property `bar` in object of type `test`.
+//!
+//! 3 | baz = bar
+//!           ^^^
+//! at test#baz (file:///tmp/test.pkl, line 3)
+//! ```
+//!
+//! [`PklDiagnostic::parse`] picks it apart into the headline, the location
+//! and the stack frames. With the `miette` feature the diagnostic can also be
+//! rendered along with the offending source.
+
+use std::fmt;
+
+/// Header pkl puts in front of every error
+const ERROR_HEADER: &str = "–– Pkl Error ––";
+
+/// A parsed pkl error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PklDiagnostic {
+    /// The first line of the message
+    pub headline: String,
+    /// The whole message, headline included, without source snippets or frames
+    pub message: String,
+    /// Stack frames, innermost first
+    pub frames: Vec<StackFrame>,
+    /// The text the diagnostic was parsed from
+    pub text: String,
+}
+
+/// One `at member (uri, line n)` entry of a pkl stack trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The member being evaluated, e.g. `test#baz`
+    pub member: String,
+    pub uri: String,
+    /// 1-based line number
+    pub line: Option<usize>,
+    /// 1-based column of the first marker under the source line
+    pub column: Option<usize>,
+    /// Number of characters marked under the source line
+    pub length: Option<usize>,
+    /// The source line pkl printed for this frame
+    pub source_line: Option<String>,
+}
+
+/// A source line printed by pkl, waiting for the frame it belongs to
+struct Snippet {
+    line: usize,
+    code: String,
+    column: Option<usize>,
+    length: Option<usize>,
+}
+
+impl PklDiagnostic {
+    /// Parse the error text of an `EvaluateResponse` or `CreateEvaluatorResponse`
+    ///
+    /// Parsing never fails, text that does not look like a pkl error ends up
+    /// in the message.
+    pub fn parse(text: &str) -> Self {
+        let lines: Vec<&str> = text.lines().collect();
+        let start = lines.iter().position(|l| l.trim() == ERROR_HEADER).map_or(0, |i| i + 1);
+
+        let mut message: Vec<&str> = vec![];
+        let mut frames = vec![];
+        let mut snippet: Option<Snippet> = None;
+        let mut in_trace = false;
+
+        let mut iter = lines[start..].iter().peekable();
+        while let Some(line) = iter.next() {
+            if let Some((number, code, prefix)) = parse_source_line(line) {
+                in_trace = true;
+                let marker = iter.peek().and_then(|next| parse_marker(next, prefix));
+                if marker.is_some() {
+                    iter.next();
+                }
+
+                snippet = Some(Snippet {
+                    line: number,
+                    code: code.to_string(),
+                    column: marker.map(|m| m.0),
+                    length: marker.map(|m| m.1),
+                });
+            } else if let Some(frame) = parse_frame(line) {
+                in_trace = true;
+                frames.push(frame.with_snippet(snippet.take()));
+            } else if !in_trace {
+                message.push(line);
+            }
+        }
+
+        while message.last().is_some_and(|l| l.trim().is_empty()) {
+            message.pop();
+        }
+        let message = message.join("\n").trim().to_string();
+
+        Self {
+            headline: message.lines().next().unwrap_or_default().to_string(),
+            message,
+            frames,
+            text: text.to_string(),
+        }
+    }
+
+    /// The innermost frame, which is where the error occurred
+    pub fn location(&self) -> Option<&StackFrame> {
+        self.frames.first()
+    }
+
+    /// URI of the module the error occurred in
+    pub fn uri(&self) -> Option<&str> {
+        self.location().map(|f| f.uri.as_str())
+    }
+
+    /// 1-based line the error occurred on
+    pub fn line(&self) -> Option<usize> {
+        self.location().and_then(|f| f.line)
+    }
+
+    /// 1-based column the error occurred at
+    pub fn column(&self) -> Option<usize> {
+        self.location().and_then(|f| f.column)
+    }
+}
+
+impl fmt::Display for PklDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.headline)?;
+        match (self.uri(), self.line(), self.column()) {
+            (Some(uri), Some(line), Some(column)) => write!(f, " ({uri}:{line}:{column})"),
+            (Some(uri), Some(line), None) => write!(f, " ({uri}:{line})"),
+            (Some(uri), None, _) => write!(f, " ({uri})"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for PklDiagnostic {}
+
+impl StackFrame {
+    fn with_snippet(mut self, snippet: Option<Snippet>) -> Self {
+        if let Some(snippet) = snippet {
+            self.line = self.line.or(Some(snippet.line));
+            self.column = snippet.column;
+            self.length = snippet.length;
+            self.source_line = Some(snippet.code);
+        }
+        self
+    }
+}
+
+/// Split `12 | foo = bar` into the line number, the code and the prefix width
+fn parse_source_line(line: &str) -> Option<(usize, &str, usize)> {
+    let (number, code) = line.split_once(" | ").or_else(|| line.strip_suffix(" |").map(|n| (n, "")))?;
+    let number = number.trim_start().parse().ok()?;
+
+    Some((number, code, line.chars().count() - code.chars().count()))
+}
+
+/// Column and length of the `^^^` marker under a source line
+fn parse_marker(line: &str, prefix: usize) -> Option<(usize, usize)> {
+    if !line.contains('^') || !line.chars().all(|c| c == ' ' || c == '^') {
+        return None;
+    }
+
+    let start = line.chars().position(|c| c == '^')?;
+    let length = line.chars().filter(|&c| c == '^').count();
+
+    Some((start.saturating_sub(prefix) + 1, length))
+}
+
+/// Parse `at member (uri, line 3)`, `at member (uri#L3)` or `at member (uri)`
+fn parse_frame(line: &str) -> Option<StackFrame> {
+    let rest = line.trim().strip_prefix("at ")?;
+    let open = rest.rfind(" (")?;
+    let member = rest[..open].to_string();
+    let location = rest[open + 2..].strip_suffix(')')?;
+
+    let (uri, line) = if let Some((uri, line)) = location.rsplit_once(", line ") {
+        (uri, line.parse().ok())
+    } else if let Some((uri, line)) = location.rsplit_once("#L").filter(|(_, l)| l.parse::<usize>().is_ok()) {
+        (uri, line.parse().ok())
+    } else {
+        (location, None)
+    };
+
+    Some(StackFrame {
+        member,
+        uri: uri.to_string(),
+        line,
+        column: None,
+        length: None,
+        source_line: None,
+    })
+}
+
+#[cfg(feature = "miette")]
+mod render {
+    use std::fs;
+
+    use miette::{Diagnostic, GraphicalReportHandler, GraphicalTheme, LabeledSpan, NamedSource, SourceSpan};
+    use url::Url;
+
+    use super::{PklDiagnostic, StackFrame};
+
+    /// The diagnostic together with the source it points into
+    #[derive(Debug)]
+    pub struct Rendered {
+        diagnostic: PklDiagnostic,
+        source: Option<NamedSource<String>>,
+        span: Option<SourceSpan>,
+    }
+
+    impl std::fmt::Display for Rendered {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.diagnostic.message)
+        }
+    }
+
+    impl std::error::Error for Rendered {}
+
+    impl Diagnostic for Rendered {
+        fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+            self.source.as_ref().map(|s| s as &dyn miette::SourceCode)
+        }
+
+        fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+            let span = self.span?;
+            let label = self.diagnostic.location().map(|f| f.member.clone());
+            Some(Box::new(std::iter::once(LabeledSpan::new_with_span(label, span))))
+        }
+
+        fn help(&self) -> Option<Box<dyn std::fmt::Display + '_>> {
+            let trace: Vec<String> = self.diagnostic.frames.iter()
+                .map(|f| match f.line {
+                    Some(line) => format!("at {} ({}, line {line})", f.member, f.uri),
+                    None => format!("at {} ({})", f.member, f.uri),
+                })
+                .collect();
+
+            if trace.is_empty() {
+                return None;
+            }
+            Some(Box::new(trace.join("\n")))
+        }
+    }
+
+    /// The module text for `frame`, read from disk for `file:` URIs
+    ///
+    /// Falls back to the single line pkl printed, padded so that it keeps
+    /// its line number. Frames without a line, or on line 0 as synthetic
+    /// frames may report, have no source.
+    fn load_source(frame: &StackFrame) -> Option<String> {
+        let line = frame.line.filter(|&line| line > 0)?;
+
+        let from_disk = Url::parse(&frame.uri).ok()
+            .filter(|url| url.scheme() == "file")
+            .and_then(|url| url.to_file_path().ok())
+            .and_then(|path| fs::read_to_string(path).ok())
+            .filter(|text| text.lines().nth(line - 1).map(str::trim_end) == frame.source_line.as_deref().map(str::trim_end));
+
+        from_disk.or_else(|| frame.source_line.as_ref().map(|code| "\n".repeat(line - 1) + code))
+    }
+
+    fn span(source: &str, frame: &StackFrame) -> Option<SourceSpan> {
+        let line = source.split_inclusive('\n').nth(frame.line?.checked_sub(1)?)?;
+        let line_start = line.as_ptr() as usize - source.as_ptr() as usize;
+
+        let mut chars = line.char_indices().skip(frame.column?.saturating_sub(1));
+        let (start, _) = chars.next()?;
+        let end = chars.nth(frame.length?.saturating_sub(1)).map_or(line.trim_end().len(), |(i, _)| i);
+
+        Some(SourceSpan::new((line_start + start).into(), end.saturating_sub(start).max(1)))
+    }
+
+    impl PklDiagnostic {
+        /// Pair the diagnostic with the source it points to, ready for miette to report
+        pub fn to_report(&self) -> miette::Report {
+            let location = self.location();
+            let text = location.and_then(load_source);
+
+            let span = location.zip(text.as_deref()).and_then(|(frame, text)| span(text, frame));
+            let source = text.zip(location).map(|(text, frame)| NamedSource::new(&frame.uri, text));
+
+            miette::Report::new(Rendered { diagnostic: self.clone(), source, span })
+        }
+
+        /// Render the diagnostic with the offending source snippet, without colors
+        pub fn render(&self) -> String {
+            let mut out = String::new();
+            let report = self.to_report();
+
+            GraphicalReportHandler::new_themed(GraphicalTheme::unicode_nocolor())
+                .render_report(&mut out, report.as_ref())
+                .expect("writing to a String can't fail");
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_ERROR: &str = "–– Pkl Error ––
+Expected value of type `Int`, but got type `String`.
+Value: \"hello\"
+
+3 | foo: Int = \"hello\"
+               ^^^^^^^
+at test#foo (file:///tmp/test.pkl, line 3)
+
+106 | text = renderer.renderDocument(value)
+             ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+at pkl.base#Module.output.text (https://github.com/apple/pkl/blob/0.25.3/stdlib/base.pkl#L106)
+";
+
+    #[test]
+    fn test_parse_diagnostic() {
+        let diagnostic = PklDiagnostic::parse(TYPE_ERROR);
+
+        assert_eq!(diagnostic.headline, "Expected value of type `Int`, but got type `String`.");
+        assert_eq!(diagnostic.message, "Expected value of type `Int`, but got type `String`.\nValue: \"hello\"");
+        assert_eq!(diagnostic.uri(), Some("file:///tmp/test.pkl"));
+        assert_eq!(diagnostic.line(), Some(3));
+        assert_eq!(diagnostic.column(), Some(12));
+
+        assert_eq!(diagnostic.frames.len(), 2);
+        assert_eq!(diagnostic.frames[0].member, "test#foo");
+        assert_eq!(diagnostic.frames[0].length, Some(7));
+        assert_eq!(diagnostic.frames[0].source_line.as_deref(), Some("foo: Int = \"hello\""));
+        assert_eq!(diagnostic.frames[1].uri, "https://github.com/apple/pkl/blob/0.25.3/stdlib/base.pkl");
+        assert_eq!(diagnostic.frames[1].line, Some(106));
+        assert_eq!(diagnostic.frames[1].column, Some(8));
+
+        assert_eq!(diagnostic.to_string(), "Expected value of type `Int`, but got type `String`. (file:///tmp/test.pkl:3:12)");
+    }
+
+    #[test]
+    fn test_parse_unstructured() {
+        let diagnostic = PklDiagnostic::parse("Evaluator with ID 3 was not found.");
+
+        assert_eq!(diagnostic.headline, "Evaluator with ID 3 was not found.");
+        assert!(diagnostic.frames.is_empty());
+        assert_eq!(diagnostic.uri(), None);
+    }
+
+    #[cfg(feature = "miette")]
+    #[test]
+    fn test_render() {
+        let rendered = PklDiagnostic::parse(TYPE_ERROR).render();
+
+        assert!(rendered.contains("Expected value of type `Int`"));
+        assert!(rendered.contains("3 │ foo: Int = \"hello\""));
+        assert!(rendered.contains("at test#foo (file:///tmp/test.pkl, line 3)"));
+
+        // synthetic frames may report line 0, they are rendered without a snippet
+        let rendered = PklDiagnostic::parse(&TYPE_ERROR.replace("3 |", "0 |").replace("line 3", "line 0")).render();
+        assert!(rendered.contains("at test#foo (file:///tmp/test.pkl, line 0)"));
+        assert!(!rendered.contains("foo: Int = \"hello\""));
+    }
+}
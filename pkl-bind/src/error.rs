@@ -0,0 +1,82 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::{diagnostic::PklDiagnostic, evaluator::{executor::PKL_EXEC_ENV, msg_api::frame::FrameError}};
+
+/// Errors reported by pkl-bind
+#[derive(Debug)]
+pub enum Error {
+    /// No pkl executable could be located
+    PklNotFound,
+    /// The pkl server process could not be started
+    Spawn(io::Error),
+    /// Reading from or writing to the pkl server failed
+    Transport(io::Error),
+    /// A message could not be encoded or decoded
+    Protocol(String),
+    /// pkl reported an error, the text is the server's message
+    Evaluation(String),
+    /// The result could not be decoded into the requested type
+    TypeMismatch { expected: &'static str, reason: String },
+    /// The evaluator or the connection to the server is closed
+    Closed,
+    /// A local file could not be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// A `PklProject` or its `PklProject.deps.json` lock file is invalid
+    Project(String),
+}
+
+impl Error {
+    /// Build a [`Error::TypeMismatch`] for a value that failed to decode as `expected`
+    pub fn type_mismatch(expected: &'static str, reason: impl fmt::Display) -> Self {
+        Error::TypeMismatch { expected, reason: reason.to_string() }
+    }
+
+    /// Parse the text of an [`Error::Evaluation`] into a [`PklDiagnostic`]
+    pub fn diagnostic(&self) -> Option<PklDiagnostic> {
+        match self {
+            Error::Evaluation(text) => Some(PklDiagnostic::parse(text)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PklNotFound => write!(f, "could not find pkl: set {PKL_EXEC_ENV} or add pkl to your PATH"),
+            Error::Spawn(err) => write!(f, "failed to start pkl: {err}"),
+            Error::Transport(err) => write!(f, "failed to communicate with the pkl server: {err}"),
+            Error::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            Error::Evaluation(text) => write!(f, "{text}"),
+            Error::TypeMismatch { expected, reason } => write!(f, "failed to decode the result as {expected}: {reason}"),
+            Error::Closed => write!(f, "the evaluator is closed"),
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Project(reason) => write!(f, "invalid project: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Spawn(err) | Error::Transport(err) | Error::Io { source: err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::Closed => Error::Closed,
+            FrameError::Io(err) => Error::Transport(err),
+            err => Error::Protocol(err.to_string()),
+        }
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        Error::Protocol(err.to_string())
+    }
+}
@@ -1,8 +1,20 @@
+#[cfg(feature = "async")]
+pub mod async_evaluator;
+#[cfg(feature = "async")]
+pub mod async_executor;
 pub mod decoder;
 pub mod evaluator_manager;
 pub mod evaluator_options;
+#[allow(clippy::module_inception)]
 pub mod evaluator;
 pub mod executor;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake_server;
 pub mod logger;
 pub mod module_source;
 pub mod msg_api;
+pub mod output_files;
+pub mod project;
+pub mod reader;
+mod settings;
+pub mod transport;
@@ -0,0 +1,376 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::Error;
+
+use super::{async_executor::{AsyncExecutor, AsyncSender}, decoder::Pkl, evaluator_options::EvaluatorOptions, executor::{Callbacks, PendingRequests, Received}, module_source::ModuleSource, output_files::{decode_output_files, OUTPUT_FILES_EXPR}, msg_api::{incoming::IncomingMessage, outgoing::{CloseEvaluator, Evaluate, OutgoingMessage}}};
+
+/// An evaluator living on an [`AsyncExecutor`]
+///
+/// Provides async versions of the
+/// [`EvaluatorMethods`](super::evaluator::EvaluatorMethods) operations.
+/// Several evaluations may be awaited concurrently on the same evaluator.
+pub struct AsyncEvaluator {
+    pub evaluator_id: i64,
+    pub opts: EvaluatorOptions,
+    exec: Arc<AsyncExecutor>,
+    pending_requests: PendingRequests<AsyncSender>,
+    closed: AtomicBool,
+}
+
+impl AsyncEvaluator {
+    /// Ask the server for a new evaluator configured with `options`
+    pub async fn new(exec: Arc<AsyncExecutor>, options: Option<EvaluatorOptions>) -> Result<Self, Error> {
+        let opts = options.unwrap_or_default();
+
+        let message_data = opts.create_evaluator(rand::random());
+
+        let eval_resp = match exec.senrec(OutgoingMessage::CreateEvaluator(message_data)).await? {
+            IncomingMessage::CreateEvaluatorResponse(x) => x,
+            _ => return Err(Error::Protocol("unexpected response to CreateEvaluator".into())),
+        };
+        let evaluator_id = match (eval_resp.evaluator_id, eval_resp.error) {
+            (_, Some(error)) => return Err(Error::Evaluation(error)),
+            (Some(id), None) => id,
+            (None, None) => return Err(Error::Protocol("CreateEvaluatorResponse carries neither an evaluator id nor an error".into())),
+        };
+
+        let pending_requests: PendingRequests<AsyncSender> = Default::default();
+        exec.register_evaluator(evaluator_id, pending_requests.clone(), Callbacks::new(&opts));
+
+        Ok(Self {
+            evaluator_id,
+            opts,
+            exec,
+            pending_requests,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub async fn evaluate_module<T: Pkl>(&self, source: &ModuleSource) -> Result<T, Error> {
+        T::unmarshal(self.evaluate_expression_raw(source, None).await?)
+    }
+
+    /// Evaluate `expr` within `source` and decode only its value, e.g. `"server.port"`
+    pub async fn evaluate_expression<T: Pkl>(&self, source: &ModuleSource, expr: &str) -> Result<T, Error> {
+        T::unmarshal(self.evaluate_expression_raw(source, Some(expr)).await?)
+    }
+
+    /// Evaluate `expr` within `source`, returning the pkl binary encoded result
+    pub async fn evaluate_expression_raw(&self, source: &ModuleSource, expr: Option<&str>) -> Result<Vec<u8>, Error> {
+        if self.closed() {
+            return Err(Error::Closed);
+        }
+
+        let request_id = rand::random::<i64>();
+        let (send, mut recv) = unbounded_channel();
+        self.pending_requests.lock().expect("pending requests lock poisoned").insert(request_id, send);
+
+        let msg = Evaluate {
+            request_id,
+            evaluator_id: self.evaluator_id,
+            module_uri: source.uri().to_string(),
+            module_text: source.contents().clone(),
+            expr: expr.map(str::to_string),
+        };
+
+        let res = match self.exec.send(OutgoingMessage::Evaluate(msg)).await {
+            Ok(()) => self.await_response(&mut recv).await,
+            Err(err) => Err(err),
+        };
+
+        self.pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id);
+        res
+    }
+
+    /// Evaluate the module's `output.text`
+    pub async fn evaluate_output_text(&self, source: &ModuleSource) -> Result<String, Error> {
+        let data = self.evaluate_expression_raw(source, Some("output.text")).await?;
+
+        rmp_serde::from_slice(&data).map_err(|err| Error::type_mismatch("String", err))
+    }
+
+    /// Evaluate the module's `output.files`, returning the text of every file keyed by its path
+    pub async fn evaluate_output_files(&self, source: &ModuleSource) -> Result<BTreeMap<String, String>, Error> {
+        let data = self.evaluate_expression_raw(source, Some(OUTPUT_FILES_EXPR)).await?;
+
+        decode_output_files(&data)
+    }
+
+    /// Whether [`AsyncEvaluator::close`] was called
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Close the evaluator on the server
+    pub async fn close(&self) -> Result<(), Error> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.exec.unregister_evaluator(self.evaluator_id);
+
+        let msg = CloseEvaluator { evaluator_id: Some(self.evaluator_id) };
+        self.exec.send(OutgoingMessage::CloseEvaluator(msg)).await
+    }
+
+    /// Wait for the response to an evaluate request, callbacks are answered by the reader task
+    async fn await_response(&self, recv: &mut tokio::sync::mpsc::UnboundedReceiver<Received>) -> Result<Vec<u8>, Error> {
+        match recv.recv().await.ok_or(Error::Closed)?? {
+            IncomingMessage::EvaluateResponse(x) => match (x.result, x.error) {
+                (_, Some(error)) => Err(Error::Evaluation(error)),
+                (Some(data), None) => Ok(data),
+                (None, None) => Err(Error::Protocol("EvaluateResponse carries neither a result nor an error".into())),
+            },
+            _ => Err(Error::Protocol("client received unexpected response from server".into())),
+        }
+    }
+}
+
+impl Drop for AsyncEvaluator {
+    fn drop(&mut self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.exec.unregister_evaluator(self.evaluator_id);
+
+        // closing needs to write to the server, which we can only do from a runtime
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let exec = self.exec.clone();
+            let msg = CloseEvaluator { evaluator_id: Some(self.evaluator_id) };
+            handle.spawn(async move {
+                let _ = exec.send(OutgoingMessage::CloseEvaluator(msg)).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::{mpsc, Mutex, OnceLock}, time::Duration};
+
+    use url::Url;
+
+    use crate::evaluator::{fake_server::{Action, FakeServer, FakeServerHandle}, logger::NoopLogger, module_source::text_source, msg_api::outgoing::PathElement, reader::ModuleReaderImpl};
+
+    use super::*;
+
+    /// Wait for the client to disconnect without blocking the runtime, which still has to close the evaluators
+    async fn join(server: FakeServerHandle) -> Vec<OutgoingMessage> {
+        tokio::task::spawn_blocking(move || server.join()).await.expect("fake server panicked")
+    }
+
+    #[tokio::test]
+    async fn test_new() {
+        let (exec, server) = FakeServer::new().evaluator(42).fail_create("no more evaluators").start_async();
+        let exec = Arc::new(exec);
+
+        let opts = EvaluatorOptions { output_format: "json".into(), ..Default::default() };
+        let evaluator = AsyncEvaluator::new(exec.clone(), Some(opts)).await.expect("Failed to create an evaluator");
+        assert_eq!(evaluator.evaluator_id, 42);
+
+        let res = AsyncEvaluator::new(exec.clone(), None).await;
+        assert!(matches!(res, Err(Error::Evaluation(ref x)) if x == "no more evaluators"));
+
+        drop((evaluator, exec));
+        let received = join(server).await;
+        assert!(matches!(received[0], OutgoingMessage::CreateEvaluator(ref x) if x.output_format.as_deref() == Some("json")));
+        // closed on drop
+        assert!(matches!(received.last(), Some(OutgoingMessage::CloseEvaluator(x)) if x.evaluator_id == Some(42)));
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_expression() {
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::respond_value(8080)])
+            .evaluation([Action::respond_value("port = 8080\n")])
+            .evaluation([Action::Fail("–– Pkl Error ––\nCannot find property `host`.".into())])
+            .start_async();
+        let evaluator = AsyncEvaluator::new(Arc::new(exec), None).await.expect("Failed to create an evaluator");
+        let source = text_source("port = 8080".into());
+
+        let port: u16 = evaluator.evaluate_expression(&source, "port").await.expect("Failed to obtain result");
+        assert_eq!(port, 8080);
+
+        let text = evaluator.evaluate_output_text(&source).await.expect("Failed to obtain result");
+        assert_eq!(text, "port = 8080\n");
+
+        let res = evaluator.evaluate_expression::<String>(&source, "host").await;
+        assert!(matches!(res, Err(Error::Evaluation(ref x)) if x.contains("Cannot find property")));
+
+        drop(evaluator);
+        let received = join(server).await;
+        assert!(matches!(received[1], OutgoingMessage::Evaluate(ref x) if x.expr.as_deref() == Some("port") && x.module_text.as_deref() == Some("port = 8080")));
+        assert!(matches!(received[2], OutgoingMessage::Evaluate(ref x) if x.expr.as_deref() == Some("output.text")));
+    }
+
+    #[tokio::test]
+    async fn test_module_reader() {
+        #[derive(Debug)]
+        struct Birds;
+
+        impl ModuleReaderImpl for Birds {
+            fn scheme(&self) -> &str { "birds" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { true }
+            fn is_globbable(&self) -> bool { true }
+
+            fn read(&self, uri: &Url) -> io::Result<String> {
+                match uri.path() {
+                    "/pigeon.pkl" => Ok("name = \"Pigeon\"".into()),
+                    _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("no bird at {uri}"))),
+                }
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![PathElement { name: "pigeon.pkl".into(), is_directory: false }])
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([
+                Action::ReadModule("birds:/pigeon.pkl".into()),
+                Action::log(0, "found a pigeon", "birds:/pigeon.pkl"),
+                Action::ListModules("birds:/".into()),
+                Action::respond_value("Pigeon"),
+            ])
+            .start_async();
+
+        let opts = EvaluatorOptions::hermetic().with_module_reader(Birds).with_logger(NoopLogger);
+        let evaluator = AsyncEvaluator::new(Arc::new(exec), Some(opts)).await.expect("Failed to create an evaluator");
+
+        let source = text_source("name = import(\"birds:/pigeon.pkl\").name".into());
+        let name: String = evaluator.evaluate_expression(&source, "name").await.expect("Failed to obtain result");
+        assert_eq!(name, "Pigeon");
+
+        drop(evaluator);
+        let received = join(server).await;
+        assert!(matches!(&received[2], OutgoingMessage::ReadModuleResponse(x) if x.contents.as_deref() == Some("name = \"Pigeon\"")));
+        assert!(matches!(&received[3], OutgoingMessage::ListModulesResponse(x) if x.path_elements.as_ref().unwrap()[0].name == "pigeon.pkl"));
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (exec, server) = FakeServer::new()
+            .evaluator(3)
+            .evaluation([Action::respond_value(1)])
+            .start_async();
+        let evaluator = AsyncEvaluator::new(Arc::new(exec), None).await.expect("Failed to create an evaluator");
+        let source = text_source("foo = 1".into());
+
+        assert_eq!(evaluator.evaluate_module::<i64>(&source).await.expect("Failed to obtain result"), 1);
+        assert!(!evaluator.closed());
+
+        evaluator.close().await.expect("Failed to close the evaluator");
+        assert!(evaluator.closed());
+        assert!(matches!(evaluator.evaluate_module::<i64>(&source).await, Err(Error::Closed)));
+        evaluator.close().await.expect("Closing twice is a no-op");
+
+        drop(evaluator);
+        let received = join(server).await;
+        let closes: Vec<_> = received.iter()
+            .filter_map(|m| match m {
+                OutgoingMessage::CloseEvaluator(x) => x.evaluator_id,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(closes, [3]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_evaluations() {
+        /// Reports how many evaluations were waiting when it was read
+        struct Waiting(Arc<OnceLock<PendingRequests<AsyncSender>>>);
+
+        impl ModuleReaderImpl for Waiting {
+            fn scheme(&self) -> &str { "waiting" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { false }
+            fn is_globbable(&self) -> bool { false }
+
+            fn read(&self, _uri: &Url) -> io::Result<String> {
+                let waiting = self.0.get().map_or(0, |pending| pending.lock().unwrap().len());
+                Ok(format!("count = {waiting}"))
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![])
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::ReadModule("waiting:/count.pkl".into()), Action::respond_value("first")])
+            .evaluation([Action::respond_value("second")])
+            .start_async();
+
+        let pending = Arc::new(OnceLock::new());
+        let opts = EvaluatorOptions::hermetic().with_module_reader(Waiting(pending.clone()));
+        let evaluator = AsyncEvaluator::new(Arc::new(exec), Some(opts)).await.expect("Failed to create an evaluator");
+        pending.set(evaluator.pending_requests.clone()).unwrap();
+
+        let source = text_source("x = import(\"waiting:/count.pkl\").count".into());
+        let (first, second) = tokio::join!(
+            evaluator.evaluate_expression::<String>(&source, "x"),
+            evaluator.evaluate_expression::<String>(&source, "x"),
+        );
+        assert_eq!(first.expect("Failed to obtain result"), "first");
+        assert_eq!(second.expect("Failed to obtain result"), "second");
+
+        drop(evaluator);
+        let received = join(server).await;
+        let reads: Vec<_> = received.iter().filter(|m| matches!(m, OutgoingMessage::ReadModuleResponse(_))).collect();
+        // the callback was answered while both evaluations were in flight
+        assert!(matches!(reads[..], [OutgoingMessage::ReadModuleResponse(x)] if x.contents.as_deref() == Some("count = 2")));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_reader() {
+        /// Holds the read until it is let through
+        struct Gate(Mutex<mpsc::Receiver<()>>);
+
+        impl ModuleReaderImpl for Gate {
+            fn scheme(&self) -> &str { "gate" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { false }
+            fn is_globbable(&self) -> bool { false }
+
+            fn read(&self, _uri: &Url) -> io::Result<String> {
+                self.0.lock().unwrap().recv_timeout(Duration::from_secs(5))
+                    .map(|()| "open = true".into())
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the gate was never opened"))
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![])
+            }
+        }
+
+        let (gated_exec, gated_server) = FakeServer::new()
+            .evaluation([Action::ReadModule("gate:/open.pkl".into()), Action::respond_value("through")])
+            .start_async();
+        let (exec, server) = FakeServer::new().evaluation([Action::respond_value("meanwhile")]).start_async();
+
+        let (open, gate) = mpsc::channel();
+        let opts = EvaluatorOptions::hermetic().with_module_reader(Gate(Mutex::new(gate)));
+        let gated = AsyncEvaluator::new(Arc::new(gated_exec), Some(opts)).await.expect("Failed to create an evaluator");
+        let evaluator = AsyncEvaluator::new(Arc::new(exec), None).await.expect("Failed to create an evaluator");
+
+        let source = text_source("x = import(\"gate:/open.pkl\").open".into());
+        // the test runtime has a single worker, it is stuck if the reader runs on it
+        let (through, meanwhile) = tokio::join!(
+            gated.evaluate_expression::<String>(&source, "x"),
+            async {
+                let res = evaluator.evaluate_expression::<String>(&source, "x").await;
+                open.send(()).unwrap();
+                res
+            },
+        );
+        assert_eq!(meanwhile.expect("Failed to obtain result"), "meanwhile");
+        assert_eq!(through.expect("Failed to obtain result"), "through");
+
+        drop((gated, evaluator));
+        join(server).await;
+        let received = join(gated_server).await;
+        assert!(received.iter().any(|m| matches!(m, OutgoingMessage::ReadModuleResponse(x) if x.contents.as_deref() == Some("open = true"))));
+    }
+}
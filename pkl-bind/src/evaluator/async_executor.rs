@@ -0,0 +1,335 @@
+use std::{fmt, io, process::Stdio, sync::{Arc, Weak}};
+
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, process::{Child, Command}, sync::{mpsc::{unbounded_channel, UnboundedSender}, Mutex}};
+
+use crate::Error;
+
+use super::{executor::{disconnect, dispatch, find_pkl_command, skip, Callbacks, Evaluators, ExecutorBuilder, MessageSender, PendingRequests, Received, Registration}, msg_api::{frame::{FrameError, FrameScanner, Scan, MAX_MESSAGE_SIZE}, incoming::{decode_buffered, Decoded, IncomingMessage}, outgoing::{pack_message, OutgoingMessage}}};
+
+/// Sender half used to route messages to async callers
+pub type AsyncSender = UnboundedSender<Received>;
+
+impl MessageSender for AsyncSender {
+    fn deliver(&self, msg: Received) {
+        // the receiver is gone if the caller gave up waiting
+        let _ = self.send(msg);
+    }
+}
+
+/// The async counterpart of [`super::executor::Executor`]
+///
+/// The pkl server is spawned with tokio's process API and its stdout is
+/// read by a background task, so no runtime worker is ever blocked on the
+/// pipe. [`AsyncExecutor::with_streams`] talks to a server over any other
+/// pair of async streams instead. Messages are encoded exactly as on the sync path, and the reader
+/// task answers the server's callbacks just like the sync reader thread, running the readers and
+/// loggers on tokio's blocking pool.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn run() {
+/// use std::sync::Arc;
+/// use pkl_bind::evaluator::{async_executor::AsyncExecutor, async_evaluator::AsyncEvaluator, module_source::text_source};
+///
+/// let exec = Arc::new(AsyncExecutor::new().await.expect("failed to start pkl"));
+/// let evaluator = AsyncEvaluator::new(exec, None).await.expect("failed to create evaluator");
+///
+/// let text = evaluator.evaluate_output_text(&text_source("foo = 1".into())).await;
+/// # }
+/// ```
+pub struct AsyncExecutor {
+    _child_process: Option<Child>,
+    pub version: String,
+    pub pkl_command: Vec<String>,
+    writer: AsyncWriter,
+    pending_requests: PendingRequests<AsyncSender>,
+    evaluators: Evaluators<AsyncSender>,
+}
+
+/// The writing half of the connection, shared by callers and the reader task
+///
+/// The reader takes it away once nothing more can be read from the server.
+type AsyncWriter = Arc<Mutex<Option<Box<dyn AsyncWrite + Send + Unpin>>>>;
+
+impl fmt::Debug for AsyncExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncExecutor")
+            .field("version", &self.version)
+            .field("pkl_command", &self.pkl_command)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExecutorBuilder {
+    /// Async version of [`ExecutorBuilder::build`]
+    ///
+    /// Must be called from within a tokio runtime.
+    pub async fn build_async(self) -> Result<AsyncExecutor, Error> {
+        AsyncExecutor::spawn(find_pkl_command(self.into_pkl_command())?).await
+    }
+}
+
+impl AsyncExecutor {
+    /// Locate pkl and spawn a server using the default lookup order
+    pub async fn new() -> Result<Self, Error> {
+        ExecutorBuilder::default().build_async().await
+    }
+
+    async fn spawn(pkl_command: Vec<String>) -> Result<Self, Error> {
+        let (program, args) = pkl_command.split_first().ok_or(Error::PklNotFound)?;
+
+        // Checking the version of pkl on the host
+        let version_check = Command::new(program)
+                                .args(&args[..args.len() - 1])
+                                .arg("--version")
+                                .stdout(Stdio::piped())
+                                .output()
+                                .await
+                                .map_err(Error::Spawn)?;
+        let version = String::from_utf8_lossy(&version_check.stdout).trim().to_string();
+
+        // nothing would drain a piped stderr, a chatty server would block once it filled up
+        let mut child_process = Command::new(program)
+                                .args(args)
+                                .stdin(Stdio::piped())
+                                .stdout(Stdio::piped())
+                                .stderr(Stdio::inherit())
+                                .kill_on_drop(true)
+                                .spawn()
+                                .map_err(Error::Spawn)?;
+
+        let child_in = child_process.stdin.take().expect("stdin is piped");
+        let child_out = child_process.stdout.take().expect("stdout is piped");
+
+        let mut exec = Self::with_streams(child_out, child_in);
+        exec._child_process = Some(child_process);
+        exec.version = version;
+        exec.pkl_command = pkl_command;
+        Ok(exec)
+    }
+
+    /// Communicate with a pkl server reading from `reader` and writing to `writer`
+    ///
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # async fn run() {
+    /// use pkl_bind::evaluator::async_executor::AsyncExecutor;
+    ///
+    /// let (client, _server) = tokio::io::duplex(64 * 1024);
+    /// let (reader, writer) = tokio::io::split(client);
+    /// let exec = AsyncExecutor::with_streams(reader, writer);
+    /// # }
+    /// ```
+    pub fn with_streams<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let pending_requests: PendingRequests<AsyncSender> = Default::default();
+        let evaluators: Evaluators<AsyncSender> = Default::default();
+
+        let writer: AsyncWriter = Arc::new(Mutex::new(Some(Box::new(writer))));
+
+        // the reader must not keep the connection open once the executor is dropped
+        tokio::spawn(read_loop(reader, Arc::downgrade(&writer), pending_requests.clone(), evaluators.clone()));
+
+        Self {
+            _child_process: None,
+            version: Default::default(),
+            pkl_command: Default::default(),
+            writer,
+            pending_requests,
+            evaluators,
+        }
+    }
+
+    pub(crate) async fn send(&self, msg: OutgoingMessage) -> Result<(), Error> {
+        write_message(&self.writer, msg).await
+    }
+
+    /// Send a request and wait until the server replies to it
+    pub(crate) async fn senrec(&self, msg: OutgoingMessage) -> Result<IncomingMessage, Error> {
+        let request_id = msg.request_id().ok_or_else(|| Error::Protocol("message does not expect a response".into()))?;
+        let (send, mut recv) = unbounded_channel();
+
+        self.pending_requests.lock().expect("pending requests lock poisoned").insert(request_id, send);
+        let sent = self.send(msg).await;
+
+        let resp = match sent {
+            Ok(()) => recv.recv().await.ok_or(Error::Closed)?,
+            Err(err) => Err(err),
+        };
+        self.pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id);
+        resp
+    }
+
+    /// Route responses for `evaluator_id` to the given pending requests and answer its callbacks
+    pub(crate) fn register_evaluator(&self, evaluator_id: i64, pending_requests: PendingRequests<AsyncSender>, callbacks: Callbacks) {
+        let registration = Registration { pending_requests, callbacks: Arc::new(callbacks) };
+        self.evaluators.lock().expect("evaluators lock poisoned").insert(evaluator_id, registration);
+    }
+
+    /// Stop routing messages for `evaluator_id`
+    pub(crate) fn unregister_evaluator(&self, evaluator_id: i64) {
+        self.evaluators.lock().expect("evaluators lock poisoned").remove(&evaluator_id);
+    }
+}
+
+/// Encode `msg` and write it to the server in one go
+async fn write_message<W: AsyncWrite + Unpin>(writer: &Mutex<Option<W>>, msg: OutgoingMessage) -> Result<(), Error> {
+    let message: Vec<u8> = pack_message(msg)?;
+
+    let mut sender = writer.lock().await;
+    let sender = sender.as_mut().ok_or(Error::Closed)?;
+    sender.write_all(&message).await.map_err(Error::Transport)?;
+    sender.flush().await.map_err(Error::Transport)
+}
+
+/// Body of the reader task
+///
+/// Bytes are buffered until a whole message has arrived, so frames split
+/// across reads are handled transparently. The size of a message is checked
+/// as its headers come in, and scanning picks up where the previous read
+/// left off. Replies to callbacks are written to `writer` for as long as the
+/// executor holds on to it.
+async fn read_loop<R, W>(mut out: R, writer: Weak<Mutex<Option<W>>>, pending_requests: PendingRequests<AsyncSender>, evaluators: Evaluators<AsyncSender>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let mut scanner = FrameScanner::new(MAX_MESSAGE_SIZE);
+
+    let failure = 'read: loop {
+        loop {
+            let len = match scanner.scan(&buf) {
+                Ok(Scan::Complete(len)) => len,
+                Ok(Scan::Needs(_)) => break,
+                // the end of the message is unknown, there is no way to carry on
+                Err(error) => break 'read Some(error),
+            };
+
+            let decoded = decode_buffered(&buf[..len]);
+            buf.drain(..len);
+            scanner = FrameScanner::new(MAX_MESSAGE_SIZE);
+
+            match decoded {
+                Decoded::Message(msg @ (IncomingMessage::CreateEvaluatorResponse(_) | IncomingMessage::EvaluateResponse(_))) => {
+                    dispatch(msg, &pending_requests, &evaluators);
+                },
+                Decoded::Message(callback) => {
+                    // readers and loggers are user code that may block, keep them off the runtime workers
+                    let (pending, evaluators) = (pending_requests.clone(), evaluators.clone());
+                    let reply = match tokio::task::spawn_blocking(move || dispatch(callback, &pending, &evaluators)).await {
+                        Ok(reply) => reply,
+                        Err(error) => match error.try_into_panic() {
+                            Ok(panic) => std::panic::resume_unwind(panic),
+                            // the runtime is shutting down
+                            Err(_) => break 'read None,
+                        },
+                    };
+                    if let Some((reply, writer)) = reply.zip(writer.upgrade()) {
+                        // a broken connection also ends the read side
+                        let _ = write_message(&writer, reply).await;
+                    }
+                },
+                Decoded::Skipped { error, ids } => skip(error, ids, &pending_requests, &evaluators),
+                Decoded::Failed(error) => break 'read Some(error),
+                Decoded::Incomplete => break 'read None,
+            }
+        }
+
+        match out.read(&mut chunk).await {
+            Ok(0) if buf.is_empty() => break None,
+            Ok(0) => break Some(FrameError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "the stream ended mid-message"))),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(err) => break Some(FrameError::Io(err)),
+        }
+    };
+
+    // nothing sent from now on could be answered
+    if let Some(writer) = writer.upgrade() {
+        writer.lock().await.take();
+    }
+    disconnect(failure, &pending_requests, &evaluators);
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_loop_reassembles_frames() {
+        // [0x21, {requestId: 135, evaluatorId: -135901, error: null}]
+        let frame: Vec<u8> = vec![0x92, 0x21, 0x83, 0xA9, 0x72, 0x65, 0x71, 0x75, 0x65,
+                                  0x73, 0x74, 0x49, 0x64, 0xCC, 0x87, 0xAB, 0x65, 0x76,
+                                  0x61, 0x6C, 0x75, 0x61, 0x74, 0x6F, 0x72, 0x49, 0x64,
+                                  0xD2, 0xFF, 0xFD, 0xED, 0x23, 0xA5, 0x65, 0x72, 0x72,
+                                  0x6F, 0x72, 0xC0];
+
+        let pending: PendingRequests<AsyncSender> = Default::default();
+        let (send, mut recv) = unbounded_channel();
+        pending.lock().unwrap().insert(135, send);
+
+        let (mut server, client) = duplex(64);
+        let reader = tokio::spawn(read_loop(client, Weak::<Mutex<Option<tokio::io::Sink>>>::new(), pending.clone(), Default::default()));
+
+        // split the frame across two writes
+        server.write_all(&frame[..10]).await.unwrap();
+        server.flush().await.unwrap();
+        tokio::task::yield_now().await;
+        server.write_all(&frame[10..]).await.unwrap();
+
+        let msg = recv.recv().await.expect("no message dispatched").unwrap();
+        assert_eq!(msg.evaluator_id(), Some(-135901));
+
+        drop(server);
+        reader.await.unwrap();
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_loop_reports_truncated_frames() {
+        let pending: PendingRequests<AsyncSender> = Default::default();
+        let (send, mut recv) = unbounded_channel();
+        pending.lock().unwrap().insert(135, send);
+
+        let (mut server, client) = duplex(64);
+        let reader = tokio::spawn(read_loop(client, Weak::<Mutex<Option<tokio::io::Sink>>>::new(), pending.clone(), Default::default()));
+
+        // [0x21, {requestId: ...}], then the server goes away
+        server.write_all(&[0x92, 0x21, 0x81, 0xA9, b'r', b'e', b'q']).await.unwrap();
+        drop(server);
+
+        let err = recv.recv().await.expect("no error delivered").unwrap_err();
+        assert!(matches!(err, Error::Transport(ref e) if e.kind() == io::ErrorKind::UnexpectedEof), "{err}");
+        reader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_loop_rejects_oversized_headers() {
+        let pending: PendingRequests<AsyncSender> = Default::default();
+        let (send, mut recv) = unbounded_channel();
+        pending.lock().unwrap().insert(135, send);
+
+        let writer = Arc::new(Mutex::new(Some(tokio::io::sink())));
+        let (mut server, client) = duplex(64);
+        let reader = tokio::spawn(read_loop(client, Arc::downgrade(&writer), pending.clone(), Default::default()));
+
+        // [0x21, {requestId: <bin32 of 4 GiB - 1>}], only the headers are sent and the server stays connected
+        server.write_all(&[0x92, 0x21, 0x81, 0xA9, b'r', b'e', b'q', b'u', b'e', b's', b't', b'I', b'd', 0xC6, 0xFF, 0xFF, 0xFF, 0xFF]).await.unwrap();
+
+        let err = recv.recv().await.expect("no error delivered").unwrap_err();
+        assert!(matches!(err, Error::Protocol(ref x) if x.contains("exceeds the limit")), "{err}");
+        reader.await.unwrap();
+        assert!(pending.lock().unwrap().is_empty());
+        assert!(writer.lock().await.is_none());
+    }
+}
@@ -7,7 +7,30 @@
 /// and rmp_serde that handles basic datatypes and
 /// pkl inheritance in an idiomatic rust style.
 pub trait Pkl {
-    fn unmarshal(data: Vec<u8>) -> Result<Self, &'static str> where Self: Sized;
+    fn unmarshal(data: Vec<u8>) -> Result<Self, crate::Error> where Self: Sized;
+}
+
+/// Primitive pkl values are encoded as plain msgpack values
+macro_rules! impl_pkl_primitive {
+    ($($ty:ty),*) => {$(
+        impl Pkl for $ty {
+            fn unmarshal(data: Vec<u8>) -> Result<Self, crate::Error> {
+                rmp_serde::from_slice(&data).map_err(|err| crate::Error::type_mismatch(stringify!($ty), err))
+            }
+        }
+    )*};
+}
+
+impl_pkl_primitive!(bool, i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, String);
+
+/// `null` decodes to `None`
+impl<T: Pkl> Pkl for Option<T> {
+    fn unmarshal(data: Vec<u8>) -> Result<Self, crate::Error> {
+        match data.as_slice() {
+            [0xC0] => Ok(None),
+            _ => T::unmarshal(data).map(Some),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1,32 +1,51 @@
-use std::{sync::mpsc::{Sender, Receiver, channel}, any::Any, collections::HashMap, rc::Rc};
+use std::{sync::{atomic::{AtomicBool, Ordering}, mpsc::channel, Arc}, any::Any, collections::BTreeMap};
 
-use crate::evaluator::msg_api::incoming::IncomingMessage;
+use crate::{evaluator::msg_api::incoming::IncomingMessage, Error};
 
-use super::{msg_api::{outgoing::{ResourceReader, ModuleReader, Evaluate}, incoming::EvaluateResponse}, module_source::ModuleSource, logger::Logger, evaluator_options::EvaluatorOptions, evaluator_manager::EvaluatorManager};
+use super::{decoder::Pkl, msg_api::outgoing::{Evaluate, OutgoingMessage, CloseEvaluator}, module_source::ModuleSource, evaluator_options::EvaluatorOptions, executor::{Callbacks, Executor, PendingRequests, Received}, output_files::{decode_output_files, OUTPUT_FILES_EXPR}};
 
-// Interface for evaluating pkl modules
-pub struct Evaluator { // NOTE the lifetime allows us to ignore close() since at the end of the lifetime the Evaluator is killed automatically
+/// Interface for evaluating pkl modules
+///
+/// An evaluator is created by
+/// [`EvaluatorManager::new_evaluator`](super::evaluator_manager::EvaluatorManager::new_evaluator)
+/// and shares the manager's connection to the pkl server. An evaluator can
+/// be reused for any number of evaluations until it is closed, either
+/// explicitly with [`EvaluatorMethods::close`] or when it is dropped.
+pub struct Evaluator {
     pub evaluator_id: i64,
-    pub logger: Logger,
-    // pub manager: Option<Rc<&EvaluatorManager>>, //TODO fix the bidirectional reference
-    pub pending_requests: HashMap<i64, Sender<EvaluateResponse>>,
-    pub closed: bool,
-    pub resource_readers: Vec<ResourceReader>,
-    pub module_readers: Vec<ModuleReader>,
+    exec: Arc<Executor>,
+    pub pending_requests: PendingRequests,
+    closed: AtomicBool,
     pub opts: EvaluatorOptions,
 }
 
-impl Default for Evaluator {
-    fn default() -> Self {
+impl Evaluator {
+    /// Start routing the server's messages for `evaluator_id` to a new handle
+    pub(crate) fn new(evaluator_id: i64, exec: Arc<Executor>, opts: EvaluatorOptions) -> Self {
+        let pending_requests: PendingRequests = Default::default();
+        exec.register_evaluator(evaluator_id, pending_requests.clone(), Callbacks::new(&opts));
+
         Self {
-            evaluator_id: rand::random(),
-            logger: Default::default(),
-            // manager: Default::default(),
-            pending_requests: Default::default(),
-            closed: Default::default(),
-            resource_readers: Default::default(),
-            module_readers: Default::default(),
-            opts: Default::default()
+            evaluator_id,
+            exec,
+            pending_requests,
+            closed: AtomicBool::new(false),
+            opts,
+        }
+    }
+
+    /// Wait for the response to an evaluate request
+    ///
+    /// Callbacks made while evaluating are answered by the reader thread.
+    fn await_response(&self, recv: std::sync::mpsc::Receiver<Received>) -> Result<Vec<u8>, Error> {
+        match recv.recv().map_err(|_| Error::Closed)? {
+            Ok(IncomingMessage::EvaluateResponse(x)) => match (x.result, x.error) {
+                (_, Some(error)) => Err(Error::Evaluation(error)),
+                (Some(data), None) => Ok(data),
+                (None, None) => Err(Error::Protocol("EvaluateResponse carries neither a result nor an error".into())),
+            },
+            Ok(_) => Err(Error::Protocol("client received unexpected response from server".into())),
+            Err(err) => Err(err),
         }
     }
 }
@@ -35,56 +54,508 @@ impl Default for Evaluator {
 //  macro since we can evaluate what the type is at compile
 //  time. //NOTE I'm dumb and wrong...
 pub trait EvaluatorMethods { // NOTE this allows for other types of evaluators, which could be nice
-    fn evaluate_module<T>(&self, source: &ModuleSource) -> Result<T, &'static str>;
-    fn evaluate_output_text(&self, source: &ModuleSource) -> Result<&'static str, &'static str>;
-    fn evaluate_output_value(&self, source: &ModuleSource, out: &dyn Any) -> Result<&'static str, &'static str>;
-    fn evaluate_output_files(&self, source: &ModuleSource) -> Result<&'static str, &'static str>;
-    fn evaluate_expression<T>(&self, source: &ModuleSource, expr: Option<String>) -> Result<T, &'static str>;
-    fn evaluate_expression_raw<T>(&self, source: &ModuleSource, expr: Option<String>) -> Result<T, &'static str>;
+    fn evaluate_module<T: Pkl>(&self, source: &ModuleSource) -> Result<T, Error>;
+    fn evaluate_output_text(&self, source: &ModuleSource) -> Result<String, Error>;
+    fn evaluate_output_value(&self, source: &ModuleSource, out: &dyn Any) -> Result<&'static str, Error>;
+    fn evaluate_output_files(&self, source: &ModuleSource) -> Result<BTreeMap<String, String>, Error>;
+    fn evaluate_expression<T: Pkl>(&self, source: &ModuleSource, expr: &str) -> Result<T, Error>;
+    fn evaluate_expression_raw(&self, source: &ModuleSource, expr: Option<&str>) -> Result<Vec<u8>, Error>;
     fn closed(&self, ) -> bool;
-    fn close(&self);
+    fn close(&self) -> Result<(), Error>;
 }
 
 impl EvaluatorMethods for Evaluator {
-    fn evaluate_module<T>(&self, source: &ModuleSource) -> Result<T, &'static str> {
-        return self.evaluate_expression(source, None);
+    fn evaluate_module<T: Pkl>(&self, source: &ModuleSource) -> Result<T, Error> {
+        T::unmarshal(self.evaluate_expression_raw(source, None)?)
     }
 
-    fn evaluate_expression<T>(&self, source: &ModuleSource, expr: Option<String>) -> Result<T, &'static str> {
-        return self.evaluate_expression_raw(source, expr);
+    /// Evaluate `expr` within `source` and decode only its value
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use pkl_bind::evaluator::{evaluator::EvaluatorMethods, evaluator_manager::EvaluatorManager, module_source::text_source};
+    ///
+    /// let manager = EvaluatorManager::new().expect("failed to start pkl");
+    /// let evaluator = manager.new_evaluator(None).expect("failed to create an evaluator");
+    ///
+    /// let source = text_source("server { port = 8080 }".into());
+    /// let port: i64 = evaluator.evaluate_expression(&source, "server.port").unwrap();
+    /// ```
+    fn evaluate_expression<T: Pkl>(&self, source: &ModuleSource, expr: &str) -> Result<T, Error> {
+        T::unmarshal(self.evaluate_expression_raw(source, Some(expr))?)
     }
 
-    fn evaluate_expression_raw<T>(&self, source: &ModuleSource, expr: Option<String>) -> Result<T, &'static str> {
+    /// Evaluate `expr` within `source`, or the whole module if `None`, returning the pkl binary encoded result
+    fn evaluate_expression_raw(&self, source: &ModuleSource, expr: Option<&str>) -> Result<Vec<u8>, Error> {
+        if self.closed() {
+            return Err(Error::Closed);
+        }
+
+        // register with the reader thread before sending the evaluate request
         let request_id: i64 = rand::random::<i64>();
-        let (send, recv) = channel::<IncomingMessage>();
+        let (send, recv) = channel::<Received>();
+        self.pending_requests.lock().expect("pending requests lock poisoned").insert(request_id, send);
 
         let msg = Evaluate {
             request_id,
             evaluator_id: self.evaluator_id,
             module_uri: source.uri().to_string(),
             module_text: source.contents().clone(), //FIXME badness
-            expr,
+            expr: expr.map(str::to_string),
         };
-        todo!()
+
+        let res = self.exec.send(OutgoingMessage::Evaluate(msg))
+            .and_then(|()| self.await_response(recv));
+
+        self.pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id);
+        res
     }
 
-    fn evaluate_output_text(&self, source: &ModuleSource) -> Result<&'static str, &'static str> {
-        todo!()
+    /// Evaluate the module's `output.text`
+    ///
+    /// The text is rendered with the module's own renderer, or the one
+    /// selected by [`EvaluatorOptions::output_format`] if the module does not
+    /// set one.
+    fn evaluate_output_text(&self, source: &ModuleSource) -> Result<String, Error> {
+        let data = self.evaluate_expression_raw(source, Some("output.text"))?;
+
+        rmp_serde::from_slice(&data).map_err(|err| Error::type_mismatch("String", err))
     }
 
-    fn evaluate_output_value(&self, source: &ModuleSource, out: &dyn Any) -> Result<&'static str, &'static str> {
+    fn evaluate_output_value(&self, _source: &ModuleSource, _out: &dyn Any) -> Result<&'static str, Error> {
         todo!()
     }
 
-    fn evaluate_output_files(&self, source: &ModuleSource) -> Result<&'static str, &'static str> {
-        todo!()
+    /// Evaluate the module's `output.files`, returning the text of every file keyed by its path
+    ///
+    /// See [`write_output_files`](super::output_files::write_output_files)
+    /// to write them to disk.
+    fn evaluate_output_files(&self, source: &ModuleSource) -> Result<BTreeMap<String, String>, Error> {
+        let data = self.evaluate_expression_raw(source, Some(OUTPUT_FILES_EXPR))?;
+
+        decode_output_files(&data)
     }
 
     fn closed(&self, ) -> bool {
-        todo!()
+        self.closed.load(Ordering::SeqCst)
     }
 
-    fn close(&self) {
-        todo!()
+    /// Close the evaluator on the server, later evaluations fail with [`Error::Closed`]
+    ///
+    /// Closing an evaluator that is already closed does nothing.
+    fn close(&self) -> Result<(), Error> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.exec.unregister_evaluator(self.evaluator_id);
+
+        let msg = CloseEvaluator {
+            evaluator_id: Some(self.evaluator_id),
+        };
+        self.exec.send(OutgoingMessage::CloseEvaluator(msg))
+    }
+}
+
+impl Drop for Evaluator {
+    fn drop(&mut self) {
+        // the server may already be gone, there is nothing left to clean up then
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkl_derive::Pkl;
+    use url::Url;
+
+    use crate::evaluator::{evaluator_manager::EvaluatorManager, fake_server::{Action, FakeServer}, module_source::{text_source, uri_source}};
+
+    use super::*;
+
+    #[test]
+    fn test_standard_pipeline() {
+        // Fails since we need our own macro to deserialize this
+        #[derive(Debug, Pkl)]
+        struct Test {
+            foo: i64,
+            bar: i32,
+        }
+
+        // Test {foo: 1, bar: 2}
+        let data = vec![0x94, 0x01, 0xA4, 0x54, 0x65, 0x73, 0x74, 0xD9, 0x44, 0x66, 0x69, 0x6C, 0x65,
+                        0x3A, 0x2F, 0x2F, 0x2F, 0x68, 0x6F, 0x6D, 0x65, 0x2F, 0x73, 0x74, 0x6F, 0x72,
+                        0x6D, 0x62, 0x6C, 0x65, 0x73, 0x73, 0x65, 0x64, 0x2F, 0x43, 0x6F, 0x64, 0x65,
+                        0x2F, 0x70, 0x6B, 0x6C, 0x2D, 0x72, 0x75, 0x73, 0x74, 0x2F, 0x73, 0x72, 0x63,
+                        0x2F, 0x65, 0x76, 0x61, 0x6C, 0x75, 0x61, 0x74, 0x6F, 0x72, 0x2F, 0x74, 0x65,
+                        0x73, 0x74, 0x73, 0x2F, 0x74, 0x65, 0x73, 0x74, 0x2E, 0x70, 0x6B, 0x6C, 0x92,
+                        0x93, 0x10, 0xA3, 0x66, 0x6F, 0x6F, 0x01, 0x93, 0x10, 0xA3, 0x62, 0x61, 0x72,
+                        0x02];
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::ListModules("file:///".into()), Action::Respond(data)])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+
+        let source = uri_source(Url::parse("file:///tests/test.pkl").unwrap());
+        let test: Test = evaluator.evaluate_module::<Test>(&source).expect("Failed to obtain result");
+
+        assert_eq!(test.foo, 1);
+        assert_eq!(test.bar, 2);
+
+        drop((evaluator, eval));
+        let received = server.join();
+        assert!(matches!(received[1], OutgoingMessage::Evaluate(ref x) if x.module_uri == "file:///tests/test.pkl"));
+        assert!(matches!(received[2], OutgoingMessage::ListModulesResponse(_)));
+    }
+
+    #[test]
+    fn test_evaluation_error() {
+        #[derive(Debug, Pkl)]
+        struct Test {
+            _foo: i64,
+        }
+
+        let (exec, _server) = FakeServer::new()
+            .evaluation([Action::Fail("–– Pkl Error ––\nI/O error loading module.".into())])
+            .evaluation([Action::respond_value("not a struct")])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let source = uri_source(Url::parse("file:///tests/test.pkl").unwrap());
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let res = evaluator.evaluate_module::<Test>(&source);
+        assert!(matches!(res, Err(Error::Evaluation(ref x)) if x.contains("I/O error")));
+
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let res = evaluator.evaluate_module::<Test>(&source);
+        assert!(matches!(res, Err(Error::TypeMismatch { expected: "Test", .. })));
+    }
+
+    #[test]
+    fn test_output_text() {
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::respond_value("{\n  \"foo\": 1\n}\n")])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let opts = EvaluatorOptions { output_format: "json".into(), ..Default::default() };
+        let evaluator = eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator");
+
+        let text = evaluator.evaluate_output_text(&text_source("foo = 1".into())).expect("Failed to obtain result");
+        assert_eq!(text, "{\n  \"foo\": 1\n}\n");
+
+        drop((evaluator, eval));
+        let received = server.join();
+        assert!(matches!(received[0], OutgoingMessage::CreateEvaluator(ref x) if x.output_format.as_deref() == Some("json")));
+        assert!(matches!(received[1], OutgoingMessage::Evaluate(ref x) if x.expr.as_deref() == Some("output.text") && x.module_text.as_deref() == Some("foo = 1")));
+    }
+
+    #[test]
+    fn test_output_files() {
+        // pkl encodes a Map as [0x02, {path: text}]
+        let files = BTreeMap::from([("a.yaml", "a: 1\n"), ("b/c.json", "{}\n")]);
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::respond_value((0x02, &files))])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+
+        let res = evaluator.evaluate_output_files(&text_source("output { files {} }".into())).expect("Failed to obtain result");
+        assert_eq!(res.len(), 2);
+        assert_eq!(res["b/c.json"], "{}\n");
+
+        drop((evaluator, eval));
+        assert!(matches!(server.join()[1], OutgoingMessage::Evaluate(ref x) if x.expr.as_deref() == Some(OUTPUT_FILES_EXPR)));
+    }
+
+    #[test]
+    fn test_evaluate_expression() {
+        // Test {foo: 1, bar: 2}
+        let data = vec![0x94, 0x01, 0xA4, 0x54, 0x65, 0x73, 0x74, 0xA9, 0x72, 0x65, 0x70, 0x6C,
+                        0x3A, 0x74, 0x65, 0x78, 0x74, 0x92, 0x93, 0x10, 0xA3, 0x66, 0x6F, 0x6F,
+                        0x01, 0x93, 0x10, 0xA3, 0x62, 0x61, 0x72, 0x02];
+
+        #[derive(Debug, Pkl)]
+        struct Test {
+            foo: i64,
+            bar: i32,
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::Respond(data)])
+            .evaluation([Action::respond_value(8080)])
+            .evaluation([Action::respond_value(())])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let source = text_source("server { test = new Test { foo = 1; bar = 2 }; port = 8080 }".into());
+
+        let test: Test = evaluator.evaluate_expression(&source, "server.test").expect("Failed to obtain result");
+        assert_eq!((test.foo, test.bar), (1, 2));
+
+        let port: u16 = evaluator.evaluate_expression(&source, "server.port").expect("Failed to obtain result");
+        assert_eq!(port, 8080);
+
+        let host: Option<String> = evaluator.evaluate_expression(&source, "server.host").expect("Failed to obtain result");
+        assert_eq!(host, None);
+
+        drop((evaluator, eval));
+        assert!(matches!(server.join()[1], OutgoingMessage::Evaluate(ref x) if x.expr.as_deref() == Some("server.test")));
+    }
+
+    #[test]
+    fn test_reuse_and_close() {
+        let (exec, server) = FakeServer::new()
+            .evaluator(3)
+            .evaluation([Action::respond_value(1)])
+            .evaluation([Action::respond_value(2)])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let source = text_source("foo = 1".into());
+
+        assert_eq!(evaluator.evaluate_module::<i64>(&source).expect("Failed to obtain result"), 1);
+        assert_eq!(evaluator.evaluate_module::<i64>(&source).expect("Failed to obtain result"), 2);
+        assert!(!evaluator.closed());
+
+        evaluator.close().expect("Failed to close the evaluator");
+        assert!(evaluator.closed());
+        assert!(matches!(evaluator.evaluate_module::<i64>(&source), Err(Error::Closed)));
+        evaluator.close().expect("Closing twice is a no-op");
+
+        drop((evaluator, eval));
+        let received = server.join();
+        let closes = received.iter().filter(|m| matches!(m, OutgoingMessage::CloseEvaluator(_))).count();
+        assert_eq!(closes, 1);
+        assert!(matches!(received.last(), Some(OutgoingMessage::CloseEvaluator(x)) if x.evaluator_id == Some(3)));
+    }
+
+    #[test]
+    fn test_close_on_drop() {
+        let (exec, server) = FakeServer::new().start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let first = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let second = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let ids = [first.evaluator_id, second.evaluator_id];
+
+        drop((first, second, eval));
+        let closed: Vec<i64> = server.join().into_iter()
+            .filter_map(|m| match m {
+                OutgoingMessage::CloseEvaluator(x) => x.evaluator_id,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(closed, ids);
+    }
+
+    #[test]
+    fn test_module_reader() {
+        use std::io;
+
+        use crate::evaluator::{msg_api::outgoing::PathElement, reader::ModuleReaderImpl};
+
+        struct Birds;
+
+        impl ModuleReaderImpl for Birds {
+            fn scheme(&self) -> &str { "birds" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { true }
+            fn is_globbable(&self) -> bool { true }
+
+            fn read(&self, uri: &Url) -> io::Result<String> {
+                match uri.path() {
+                    "/pigeon.pkl" => Ok("name = \"Pigeon\"".into()),
+                    _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("no bird at {uri}"))),
+                }
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![PathElement { name: "pigeon.pkl".into(), is_directory: false }])
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([
+                Action::ReadModule("birds:/pigeon.pkl".into()),
+                Action::ReadModule("birds:/parrot.pkl".into()),
+                Action::ListModules("birds:/".into()),
+                Action::ReadModule("fish:/cod.pkl".into()),
+                Action::respond_value("Pigeon"),
+            ])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let opts = EvaluatorOptions::hermetic().with_module_reader(Birds);
+        let evaluator = eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator");
+
+        let source = text_source("name = import(\"birds:/pigeon.pkl\").name".into());
+        let name: String = evaluator.evaluate_expression(&source, "name").expect("Failed to obtain result");
+        assert_eq!(name, "Pigeon");
+
+        drop((evaluator, eval));
+        let received = server.join();
+        let OutgoingMessage::CreateEvaluator(create) = &received[0] else { panic!("expected CreateEvaluator") };
+        let readers = create.client_module_readers.as_ref().unwrap();
+        assert!(readers[0].scheme == "birds" && readers[0].is_local && readers[0].is_globbable);
+        assert!(create.allowed_modules.as_ref().unwrap().contains(&"birds:".to_string()));
+
+        assert!(matches!(&received[2], OutgoingMessage::ReadModuleResponse(x) if x.contents.as_deref() == Some("name = \"Pigeon\"")));
+        assert!(matches!(&received[3], OutgoingMessage::ReadModuleResponse(x) if x.error.as_deref() == Some("no bird at birds:/parrot.pkl")));
+        assert!(matches!(&received[4], OutgoingMessage::ListModulesResponse(x) if x.path_elements.as_ref().unwrap()[0].name == "pigeon.pkl"));
+        assert!(matches!(&received[5], OutgoingMessage::ReadModuleResponse(x) if x.error.as_deref().unwrap().contains("No reader registered")));
+    }
+
+    #[test]
+    fn test_resource_reader() {
+        use std::io;
+
+        use crate::evaluator::{msg_api::outgoing::PathElement, reader::ResourceReaderImpl};
+
+        struct Flags;
+
+        impl ResourceReaderImpl for Flags {
+            fn scheme(&self) -> &str { "flags" }
+            fn has_hierarchical_uris(&self) -> bool { true }
+            fn is_globbable(&self) -> bool { true }
+
+            fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+                match uri.path() {
+                    "/dark-mode" => Ok(vec![0xFF, b'o', b'n']),
+                    _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown flag {uri}"))),
+                }
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![PathElement { name: "dark-mode".into(), is_directory: false }])
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([
+                Action::ReadResource("flags:/dark-mode".into()),
+                Action::ReadResource("flags:/beta".into()),
+                Action::ListResources("flags:/".into()),
+                Action::respond_value(true),
+            ])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let opts = EvaluatorOptions::hermetic().with_resource_reader(Flags);
+        let evaluator = eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator");
+
+        let source = text_source("dark = read(\"flags:/dark-mode\").text.endsWith(\"on\")".into());
+        let dark: bool = evaluator.evaluate_expression(&source, "dark").expect("Failed to obtain result");
+        assert!(dark);
+
+        drop((evaluator, eval));
+        let received = server.join();
+        let OutgoingMessage::CreateEvaluator(create) = &received[0] else { panic!("expected CreateEvaluator") };
+        assert_eq!(create.client_resource_readers.as_ref().unwrap()[0].scheme, "flags");
+        assert!(create.allowed_resources.as_ref().unwrap().contains(&"flags:".to_string()));
+
+        assert!(matches!(&received[2], OutgoingMessage::ReadResourceResponse(x) if x.contents.as_deref() == Some(&[0xFF, b'o', b'n'][..])));
+        assert!(matches!(&received[3], OutgoingMessage::ReadResourceResponse(x) if x.error.as_deref() == Some("unknown flag flags:/beta")));
+        assert!(matches!(&received[4], OutgoingMessage::ListResourceResponse(x) if x.path_elements.as_ref().unwrap()[0].name == "dark-mode"));
+    }
+
+    #[test]
+    fn test_logger() {
+        use std::sync::Mutex;
+
+        use crate::evaluator::logger::Logger;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Logger for Arc<Recorder> {
+            fn trace(&self, message: &str, frame_uri: &str) {
+                self.0.lock().unwrap().push(format!("trace {message} {frame_uri}"));
+            }
+
+            fn warn(&self, message: &str, frame_uri: &str) {
+                self.0.lock().unwrap().push(format!("warn {message} {frame_uri}"));
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([
+                Action::log(0, "x = 1", "repl:text"),
+                Action::log(1, "deprecated", "repl:text"),
+                Action::respond_value(1),
+            ])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let recorder = Arc::new(Recorder::default());
+        let opts = EvaluatorOptions::hermetic().with_logger(recorder.clone());
+        let evaluator = eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator");
+
+        let source = text_source("x = trace(1)".into());
+        let x: i64 = evaluator.evaluate_expression(&source, "x").expect("Failed to obtain result");
+        assert_eq!(x, 1);
+        assert_eq!(*recorder.0.lock().unwrap(), ["trace x = 1 repl:text", "warn deprecated repl:text"]);
+
+        drop((evaluator, eval));
+        server.join();
+    }
+
+    #[test]
+    fn test_concurrent_evaluations() {
+        use std::{io, sync::OnceLock, thread, time::{Duration, Instant}};
+
+        use crate::evaluator::{msg_api::outgoing::PathElement, reader::ModuleReaderImpl};
+
+        /// Only answers once both evaluations are waiting for their response
+        struct Gate(Arc<OnceLock<PendingRequests>>);
+
+        impl ModuleReaderImpl for Gate {
+            fn scheme(&self) -> &str { "gate" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { false }
+            fn is_globbable(&self) -> bool { false }
+
+            fn read(&self, _uri: &Url) -> io::Result<String> {
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while self.0.get().is_none_or(|pending| pending.lock().unwrap().len() < 2) {
+                    assert!(Instant::now() < deadline, "the second evaluation never started");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Ok("open = true".into())
+            }
+
+            fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+                Ok(vec![])
+            }
+        }
+
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::ReadModule("gate:/open.pkl".into()), Action::log(0, "through", "gate:/open.pkl"), Action::respond_value("first")])
+            .evaluation([Action::respond_value("second")])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let pending = Arc::new(OnceLock::new());
+        let opts = EvaluatorOptions::hermetic().with_module_reader(Gate(pending.clone())).with_logger(crate::evaluator::logger::NoopLogger);
+        let evaluator = eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator");
+        pending.set(evaluator.pending_requests.clone()).unwrap();
+
+        let source = text_source("x = import(\"gate:/open.pkl\").open".into());
+        let mut results: Vec<String> = thread::scope(|s| {
+            let evaluations: Vec<_> = (0..2)
+                .map(|_| s.spawn(|| evaluator.evaluate_expression::<String>(&source, "x")))
+                .collect();
+            evaluations.into_iter().map(|e| e.join().unwrap().expect("Failed to obtain result")).collect()
+        });
+        results.sort();
+        assert_eq!(results, ["first", "second"]);
+
+        drop((evaluator, eval));
+        let received = server.join();
+        let reads: Vec<_> = received.iter().filter(|m| matches!(m, OutgoingMessage::ReadModuleResponse(_))).collect();
+        assert!(matches!(reads[..], [OutgoingMessage::ReadModuleResponse(x)] if x.contents.as_deref() == Some("open = true")));
     }
 }
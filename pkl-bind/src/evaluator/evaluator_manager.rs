@@ -1,203 +1,217 @@
-use std::path::PathBuf;
+use std::{path::Path, sync::Arc};
 
-use crate::evaluator::decoder::Pkl;
+use crate::Error;
 
-use super::{evaluator::Evaluator, evaluator_options::EvaluatorOptions, msg_api::{incoming::IncomingMessage, outgoing::{OutgoingMessage, CreateEvaluator, CloseEvaluator, Evaluate, ListModulesResponse, PathElement}}};
+use super::{evaluator::Evaluator, evaluator_options::EvaluatorOptions, msg_api::{incoming::IncomingMessage, outgoing::OutgoingMessage}};
 use super::executor::Executor;
 
 
-#[derive(Default)]
+/// Creates evaluators on a shared connection to the pkl server
+///
+/// Every [`Evaluator`] keeps the connection alive, so the manager may be
+/// dropped while its evaluators are still in use.
+///
+/// # Example
+///
+/// ```no_run
+/// use pkl_bind::evaluator::{evaluator::EvaluatorMethods, evaluator_manager::EvaluatorManager, module_source::text_source};
+///
+/// let manager = EvaluatorManager::new().expect("failed to start pkl");
+/// let evaluator = manager.new_evaluator(None).expect("failed to create an evaluator");
+///
+/// let text = evaluator.evaluate_output_text(&text_source("foo = 1".into())).unwrap();
+/// ```
 pub struct EvaluatorManager {
     // interrupts: Mutex<HashMap<Sender<OutgoingMessage>, i64>>, // TODO https://docs.rs/async-map/latest/async_map/ ??
-    evaluators: Vec<Evaluator>,
-    // pending_evaluators: Evaluator,
-    exec: Executor,
+    exec: Arc<Executor>,
     // closed: AtomicBool,
     // initialized: bool,
 }
 
 impl EvaluatorManager {
-    fn close() -> Result<&'static str, &'static str> {
+    /// Create a manager backed by a pkl server found through
+    /// the default lookup (see [`crate::evaluator::executor::ExecutorBuilder`])
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self::with_executor(Executor::new()?))
+    }
+
+    /// Create a manager on top of an already configured executor
+    pub fn with_executor(exec: Executor) -> Self {
+        Self {
+            exec: Arc::new(exec),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn close() -> Result<(), Error> {
         todo!()
     }
 
-    fn get_version() -> Result<String, &'static str> {
+    #[allow(dead_code)]
+    fn get_version() -> Result<String, Error> {
         todo!()
     }
 
-    pub fn new_evaluator(&mut self, options: Option<EvaluatorOptions>) -> Result<i64, &'static str> {
-        let opts = match options {
-            None => Default::default(),
-            Some(x) => x,
-        };
+    /// Ask the server for a new evaluator configured with `options`
+    pub fn new_evaluator(&self, options: Option<EvaluatorOptions>) -> Result<Evaluator, Error> {
+        let opts = options.unwrap_or_default();
 
-        let message_data = CreateEvaluator {
-            request_id: rand::random(),
-            client_resource_readers: None,
-            client_module_readers: None,
-            module_paths: None,
-            env: None,
-            properties: None,
-            output_format: None,
-            allowed_modules: Some(opts.allowed_modules.clone()),
-            allowed_resources: Some(opts.allowed_resources.clone()),
-            root_dir: None,
-            cache_dir: Some(opts.cache_dir.to_str().unwrap().to_string()), //TODO error
-            project: None,
-            timeout_seconds: None,
-        };
+        let message_data = opts.create_evaluator(rand::random());
 
-        let eval_resp = match self.exec.senrec(OutgoingMessage::CreateEvaluator(message_data)).expect("Failed to send message") {
+        let eval_resp = match self.exec.senrec(OutgoingMessage::CreateEvaluator(message_data))? {
             IncomingMessage::CreateEvaluatorResponse(x) => x,
-            _ => panic!("Unexpected response"),
+            _ => return Err(Error::Protocol("unexpected response to CreateEvaluator".into())),
         };
 
-        let evaluator = Evaluator {
-            evaluator_id: eval_resp.evaluator_id.unwrap(), // if we did not error, then this is guaranteed
-            logger: Default::default(),
-            // manager: Some(Rc::new(self)), // FIXME see Evaluator.rs
-            pending_requests: Default::default(),
-            closed: false,
-            resource_readers: Default::default(),
-            module_readers: Default::default(),
-            opts,
+        let evaluator_id = match (eval_resp.evaluator_id, eval_resp.error) {
+            (_, Some(error)) => return Err(Error::Evaluation(error)),
+            (Some(id), None) => id,
+            (None, None) => return Err(Error::Protocol("CreateEvaluatorResponse carries neither an evaluator id nor an error".into())),
         };
 
-        let res = evaluator.evaluator_id.clone();
-
-        self.evaluators.push(evaluator);
-
-        return Ok(res);
-    }
-
-    fn new_project_evaluator() -> Result<Evaluator, &'static str> {
-        todo!()
+        Ok(Evaluator::new(evaluator_id, self.exec.clone(), opts))
     }
 
-    pub fn evaluate_module<T>(&mut self, file: String, id_number: i64) -> Result<T, &'static str> where T: Pkl + std::fmt::Debug {
-        // send the evaluate request
-        let eval_req = Evaluate {
-            request_id: rand::random::<i64>(),
-            evaluator_id: id_number,
-            module_uri: file.clone(),
-            module_text: None,
-            expr: None,
-        };
-
-        let eval_msg = OutgoingMessage::Evaluate(eval_req);
-        let mut resp = self.exec.senrec(eval_msg).expect("Failed to receive message");
-
-        loop {
-            match &mut resp {
-                IncomingMessage::EvaluateResponse(x) => {
-                    let close_msg = CloseEvaluator {
-                        evaluator_id: Some(id_number.clone()),
-                    };
-
-                    self.exec.send(OutgoingMessage::CloseEvaluator(close_msg));
-                    //TODO get the data and decode
-
-                    println!("Data: {:?}", x);
-                    let data = x.clone().result.expect("failed to get result");
-
-                    // FIXME fails to decode, need to unmarshal data
-                    print!("Data: ");
-                    for d in &data {
-                        print!("{:#04X}, ", d);
-                    }
-                    println!();
-
-                    let res = T::unmarshal(data);
-                    println!("Res: {:?}", res);
-                    return res;
-                },
-                IncomingMessage::ReadResource(x) => todo!(),
-                IncomingMessage::ReadModule(x) => todo!(),
-                IncomingMessage::ListResources(x) => todo!(),
-                IncomingMessage::ListModules(x) => {
-                    // get all the files in the module:
-                    let path = PathBuf::from(file.clone());
-                    // let mut files = file;
-                    if path.is_dir() {
-                        // files = std::fs::read_dir(path); // TODO
-                    }
-
-                    let mut modules: Vec<PathElement> = vec![];
-                    // for file in files {
-                    //     // TODO make module
-                    // }
-
-                    let list_resp = ListModulesResponse{
-                        request_id: x.request_id,
-                        evaluator_id: id_number.clone(),
-                        path_elements: Some(modules),
-                        error: None,
-                    };
-
-                    let resp = self.exec.senrec(OutgoingMessage::ListModulesResponse(list_resp)).expect("Failed to send/receive data");
-
-                },
-                IncomingMessage::Log(_) => todo!(),
-                _ => return Err("Client received unexpected response from server"),
-            }
-        }
-
-        // send the any required list_moduels response
-        // send any read_module_response
-        // send the close evaluator
+    /// Ask the server for an evaluator in the context of the `PklProject` in `project_dir`
+    ///
+    /// The project's `evaluatorSettings` and dependencies are loaded with
+    /// [`EvaluatorOptions::from_project`], so modules can import `@dep/...`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use pkl_bind::evaluator::{evaluator::EvaluatorMethods, evaluator_manager::EvaluatorManager, module_source::file_source};
+    ///
+    /// let manager = EvaluatorManager::new().expect("failed to start pkl");
+    /// let evaluator = manager.new_project_evaluator("config").expect("failed to load the project");
+    ///
+    /// let text = evaluator.evaluate_output_text(&file_source("config/app.pkl".into())).unwrap();
+    /// ```
+    pub fn new_project_evaluator(&self, project_dir: impl AsRef<Path>) -> Result<Evaluator, Error> {
+        let opts = EvaluatorOptions::from_project(self, project_dir)?;
+        self.new_evaluator(Some(opts))
     }
 
-    pub fn create_evaluator(&self, none: Option<()>) -> i64 {
+    pub fn create_evaluator(&self, _none: Option<()>) -> i64 {
         todo!()
     }
 }
 
-impl Drop for EvaluatorManager {
-    fn drop(&mut self) {
-        for evaluator in &self.evaluators {
-            let msg = CloseEvaluator {
-                evaluator_id: Some(evaluator.evaluator_id),
-            };
-            self.exec.send(OutgoingMessage::CloseEvaluator(msg));
-            // evaluator.close();
-        }
-
-        // Droppign the EvaluatorManagerExec is automatic
-        //  from the drop trait
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use pkl_derive::Pkl;
-    use crate::evaluator::decoder::Pkl;
+    use std::collections::HashMap;
+
+    use crate::evaluator::{fake_server::{Action, FakeServer}, msg_api::outgoing::OutgoingMessage, project::DEPS_FILE};
 
     use super::*;
 
     #[test]
     fn test_new_evaluator() {
-        let mut eval = EvaluatorManager::default();
+        let (exec, _server) = FakeServer::new().evaluator(7).start();
+        let eval = EvaluatorManager::with_executor(exec);
 
-        let _evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+        assert_eq!(evaluator.evaluator_id, 7);
     }
 
     #[test]
-    fn test_standard_pipeline() {
-        // Fails since we need our own macro to deserialize this
-        #[derive(Debug, Pkl)]
-        struct Test {
-            foo: i64,
-            bar: i32,
-        }
-
-        let mut eval = EvaluatorManager::default();
+    fn test_create_evaluator_options() {
+        let (exec, server) = FakeServer::new().evaluator(7).start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let opts = EvaluatorOptions {
+            env: HashMap::from([("HOME".to_string(), "/home/pkl".to_string())]),
+            properties: HashMap::from([("name".to_string(), "pigeon".to_string())]),
+            module_paths: vec!["/opt/pkl".into()],
+            output_format: "json".into(),
+            root_dir: "/work".into(),
+            project_dir: "/work".into(),
+            timeout_seconds: Some(30),
+            ..Default::default()
+        };
+        drop(eval.new_evaluator(Some(opts)).expect("Failed to create a new evaluator"));
+        drop(eval);
+
+        let OutgoingMessage::CreateEvaluator(msg) = &server.join()[0] else { panic!("expected CreateEvaluator") };
+        assert_eq!(msg.env.as_ref().unwrap()["HOME"], "/home/pkl");
+        assert_eq!(msg.properties.as_ref().unwrap()["name"], "pigeon");
+        assert_eq!(msg.module_paths.as_deref(), Some(&["/opt/pkl".to_string()][..]));
+        assert_eq!(msg.output_format.as_deref(), Some("json"));
+        assert_eq!(msg.root_dir.as_deref(), Some("/work"));
+        assert_eq!(msg.timeout_seconds, Some(30));
+        assert!(msg.client_resource_readers.is_none());
+        assert!(msg.client_module_readers.is_none());
+
+        let project = msg.project.as_ref().unwrap();
+        assert_eq!(project.r#type, "local");
+        assert_eq!(project.project_file_uri.as_deref(), Some("file:///work/PklProject"));
+    }
 
-        let evaluator = eval.new_evaluator(None).expect("Failed to create a new evaluator");
+    #[test]
+    fn test_new_project_evaluator() {
+        let dir = std::env::temp_dir().join(format!("pkl-project-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("work")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let work = dir.join("work");
+        let sha256 = "8ee9e2c3".repeat(8);
+        std::fs::write(work.join(DEPS_FILE), format!(r#"{{
+          "schemaVersion": 1,
+          "resolvedDependencies": {{
+            "package://example.com/birds@0": {{"type": "remote", "uri": "projectpackage://example.com/birds@0.5.0", "checksums": {{"sha256": "{sha256}"}}}},
+            "package://example.com/fruit@1": {{"type": "local", "uri": "projectpackage://example.com/fruit@1.2.0", "path": "../fruit"}}
+          }}
+        }}"#)).unwrap();
+
+        let settings = rmp_serde::to_vec(&(0x02, HashMap::from([("rootDir", "/work")]))).unwrap();
+        // the declared versions are older than the locked ones, which are the ones sent
+        let project = r#"{
+          "type": "local",
+          "packageUri": null,
+          "dependencies": {
+            "birds": {"type": "remote", "packageUri": "package://example.com/birds@0.4.0"},
+            "fruit": {"type": "local", "packageUri": "package://example.com/fruit@1.0.0", "projectFileUri": "file:///elsewhere/PklProject", "dependencies": {
+              "birds": {"type": "remote", "packageUri": "package://example.com/birds@0.5.0"}
+            }}
+          }
+        }"#;
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::Respond(settings)])
+            .evaluation([Action::respond_value(project)])
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
+
+        let evaluator = eval.new_project_evaluator(&work).expect("Failed to create a project evaluator");
+        assert_eq!(evaluator.opts.root_dir, "/work");
+        drop((evaluator, eval));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let received = server.join();
+        let Some(OutgoingMessage::CreateEvaluator(msg)) = received.iter().rev().find(|m| matches!(m, OutgoingMessage::CreateEvaluator(_))) else {
+            panic!("expected CreateEvaluator")
+        };
+        let project = msg.project.as_ref().expect("project is sent");
+        let dir = dir.to_string_lossy();
+        assert_eq!(project.project_file_uri, Some(format!("file://{dir}/work/PklProject")));
+
+        let birds = &project.dependencies["birds"];
+        assert_eq!(birds.r#type, "remote");
+        assert_eq!(birds.package_uri.as_deref(), Some("projectpackage://example.com/birds@0.5.0"));
+        assert_eq!(birds.checksums.as_ref().unwrap().sha256, sha256);
+
+        let fruit = &project.dependencies["fruit"];
+        assert_eq!(fruit.r#type, "local");
+        assert_eq!(fruit.package_uri.as_deref(), Some("projectpackage://example.com/fruit@1.2.0"));
+        assert_eq!(fruit.project_file_uri, Some(format!("file://{dir}/fruit/PklProject")));
+        assert_eq!(fruit.dependencies["birds"].package_uri.as_deref(), Some("projectpackage://example.com/birds@0.5.0"));
+    }
 
-        //TODO remove my paths
-        let test: Test = eval.evaluate_module::<Test>("file:///home/stormblessed/Code/pkl-rust/pkl-bind/src/evaluator/tests/test.pkl".into(), evaluator).expect("Failed to obtain result");
+    #[test]
+    fn test_new_evaluator_error() {
+        let (exec, _server) = FakeServer::new()
+            .fail_create("Cannot find module `file:///missing.pkl`.")
+            .start();
+        let eval = EvaluatorManager::with_executor(exec);
 
-        assert_eq!(test.foo, 1);
-        assert_eq!(test.bar, 2);
+        assert!(matches!(eval.new_evaluator(None), Err(Error::Evaluation(ref x)) if x.contains("missing.pkl")));
     }
 }
@@ -1,22 +1,56 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, io, path::{Path, PathBuf}, sync::Arc};
 use dirs::home_dir;
 use std::env;
 
-use super::{msg_api::outgoing::{ResourceReader, ModuleReader}, logger::Logger};
+use url::Url;
 
-//TODO documentation
-//TODO this should be taken from a pkl file in the future
+use crate::Error;
+
+use super::{msg_api::outgoing::{CreateEvaluator, ProjectOrDependency, Checksums}, logger::{Logger, StderrLogger}, evaluator::EvaluatorMethods, evaluator_manager::EvaluatorManager, module_source::file_source, settings::{self, EvaluatorSettings}, project::{decode_project, ProjectDeps, PROJECT_EXPR}, reader::{ModuleReaderImpl, ResourceReaderImpl}};
+
+/// Options used to create an evaluator
+///
+/// Start from one of the presets and refine it with the `with_*` methods:
+///
+/// ```
+/// use pkl_bind::evaluator::evaluator_options::EvaluatorOptions;
+///
+/// let opts = EvaluatorOptions::hermetic()
+///     .with_property("env", "staging")
+///     .with_module_path("/opt/pkl/lib")
+///     .with_root_dir("/srv/config");
+///
+/// assert!(opts.env.is_empty());
+/// assert!(!opts.allowed_modules.contains(&"https:".to_string()));
+/// ```
+///
+/// Settings can also be read from pkl with [`EvaluatorOptions::from_settings_file`]
+/// and [`EvaluatorOptions::from_project`].
 pub struct EvaluatorOptions {
+    /// Values read by `prop:` resources and `read("prop:...")`
     pub properties: HashMap<String, String>,
+    /// Values read by `env:` resources
     pub env: HashMap<String, String>,
+    /// Directories and archives searched by `modulepath:` URIs
     pub module_paths: Vec<String>,
-    pub logger: Logger,
+    /// Receives the messages pkl logs, see [`Logger`]
+    pub logger: Arc<dyn Logger>,
+    /// Renderer used for `output.text` when the module doesn't pick one,
+    /// e.g. `json`, `yaml`, `plist` or `properties`. Empty uses pcf.
     pub output_format: String,
+    /// URI patterns of modules that may be imported
     pub allowed_modules: Vec<String>,
+    /// URI patterns of resources that may be read
     pub allowed_resources: Vec<String>,
-    pub resource_readers: Vec<ResourceReader>,
-    pub module_readers: Vec<ModuleReader>,
+    /// Readers serving resources for custom schemes, see [`ResourceReaderImpl`]
+    pub resource_readers: Vec<Arc<dyn ResourceReaderImpl>>,
+    /// Readers serving modules for custom schemes, see [`ModuleReaderImpl`]
+    pub module_readers: Vec<Arc<dyn ModuleReaderImpl>>,
+    /// Where downloaded packages are cached, empty disables the cache
     pub cache_dir: PathBuf,
+    /// Abort evaluations that take longer than this many seconds
+    pub timeout_seconds: Option<i64>,
+    /// Restrict `file:` modules and resources to this directory
     pub root_dir: String, //TODO this should also be a path
     pub project_dir: String, //TODO this should be a path
     pub declared_project_dependency: ProjectDependencies
@@ -26,69 +60,501 @@ macro_rules! vec_of_strings {
     ($($x:expr),*) => (vec![$($x.to_string()),*]);
 }
 
+/// Module schemes allowed by [`EvaluatorOptions::preconfigured`]
+pub fn default_allowed_modules() -> Vec<String> {
+    vec_of_strings!["pkl:", "repl:", "file:", "http:", "https:", "modulepath:", "package:", "projectpackage:"]
+}
+
+/// Resource schemes allowed by [`EvaluatorOptions::preconfigured`]
+pub fn default_allowed_resources() -> Vec<String> {
+    vec_of_strings!["http:", "https:", "file:", "env:", "prop:", "modulepath:", "package:", "projectpackage:", "customfs:"]
+}
+
+/// Schemes that reach out to the network, left out by [`EvaluatorOptions::hermetic`]
+const NETWORK_SCHEMES: [&str; 4] = ["http:", "https:", "package:", "projectpackage:"];
+
+/// The default package cache, `~/.pkl/cache`, if there is a home directory
+pub fn default_cache_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".pkl").join("cache"))
+}
+
+/// The user's settings file, `~/.pkl/settings.pkl`, if there is a home directory
+pub fn default_settings_file() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".pkl").join("settings.pkl"))
+}
+
+/// The environment of this process, leaving out variables that aren't UTF-8
+pub fn os_env() -> HashMap<String, String> {
+    env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
 impl Default for EvaluatorOptions {
+    /// Same as [`EvaluatorOptions::preconfigured`]
     fn default() -> Self {
-        let allowed_resources: Vec<String> = vec_of_strings!["http:", "https:", "file:",
-                                                     "env:", "prop:", "modulepath:",
-                                                     "package:", "projectpackage:", "customfs:"];
-        let allowed_modules: Vec<String> = vec_of_strings!["pkl:", "repl:", "file:", "http:",
-                                                   "https:", "modulepath:", "package:",
-                                                   "projectpackage:"];
-        let mut dirname = home_dir().expect("No home directory found!");
-        dirname.push("/.pkl/cache");
-
-        let mut os_env: HashMap<String, String> = Default::default();
-
-        for (key, value) in env::vars_os() {
-            os_env.insert(key.to_str().expect("Failed to obtain key").into(),
-                            value.to_str().expect("Failed to obtain value for key").into());
-        }
+        Self::preconfigured()
+    }
+}
 
+impl EvaluatorOptions {
+    /// Options with nothing allowed and nothing set
+    pub fn empty() -> Self {
         Self {
             properties: Default::default(),
-            env: os_env,
+            env: Default::default(),
             module_paths: Default::default(),
-            logger: Default::default(),
+            logger: Arc::new(StderrLogger),
             output_format: Default::default(),
-            allowed_modules,
-            allowed_resources,
+            allowed_modules: Default::default(),
+            allowed_resources: Default::default(),
             resource_readers: Default::default(),
             module_readers: Default::default(),
-            cache_dir: dirname,
+            cache_dir: Default::default(),
+            timeout_seconds: None,
             root_dir: Default::default(),
             project_dir: Default::default(),
             declared_project_dependency: Default::default(),
         }
     }
+
+    /// The options `pkl eval` runs with: the OS environment, the default
+    /// allowed modules and resources, and `~/.pkl/cache` as package cache
+    pub fn preconfigured() -> Self {
+        Self::empty()
+            .with_os_env()
+            .with_default_allowed_modules()
+            .with_default_allowed_resources()
+            .with_default_cache_dir()
+    }
+
+    /// Options that only depend on what is passed in: no environment
+    /// variables, no package cache and no network schemes
+    pub fn hermetic() -> Self {
+        let local = |schemes: Vec<String>| schemes.into_iter().filter(|s| !NETWORK_SCHEMES.contains(&s.as_str())).collect();
+
+        Self {
+            allowed_modules: local(default_allowed_modules()),
+            allowed_resources: local(default_allowed_resources()),
+            ..Self::empty()
+        }
+    }
+
+    /// Options from the evaluator settings in a `settings.pkl` file
+    ///
+    /// The file either amends `pkl:EvaluatorSettings` or holds them in its
+    /// `evaluatorSettings` property. Settings that are left unset keep their
+    /// [`EvaluatorOptions::preconfigured`] value, and relative paths are
+    /// resolved against the directory of the file.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use pkl_bind::evaluator::{evaluator_manager::EvaluatorManager, evaluator_options::{default_settings_file, EvaluatorOptions}};
+    ///
+    /// let manager = EvaluatorManager::new().expect("failed to start pkl");
+    /// let path = default_settings_file().expect("no home directory");
+    /// let opts = EvaluatorOptions::from_settings_file(&manager, &path).expect("failed to read settings");
+    /// let evaluator = manager.new_evaluator(Some(opts)).expect("failed to create an evaluator");
+    /// ```
+    pub fn from_settings_file(manager: &EvaluatorManager, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let evaluator = manager.new_evaluator(Some(Self::preconfigured()))?;
+        let data = evaluator.evaluate_expression_raw(&file_source(path.to_path_buf()), Some(&settings::settings_file_expr()))?;
+        evaluator.close()?;
+
+        let settings = EvaluatorSettings::decode(&data)?;
+
+        Ok(settings.apply(Self::preconfigured(), path.parent().unwrap_or(Path::new(""))))
+    }
+
+    /// Options for the `PklProject` in `project_dir`
+    ///
+    /// The project's `evaluatorSettings` are applied like in
+    /// [`EvaluatorOptions::from_settings_file`], and its dependencies are sent
+    /// to the server at the versions locked in `PklProject.deps.json`, so
+    /// `@dep/...` imports resolve. A missing lock file, or one that doesn't
+    /// cover the declared versions, is reported as [`Error::Project`].
+    /// `project_dir` may be relative, it is resolved against the current directory.
+    pub fn from_project(manager: &EvaluatorManager, project_dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let project_dir = project_dir.as_ref();
+        // the server needs absolute `file:` URIs
+        let project_dir = &project_dir.canonicalize().map_err(|source| Error::Io { path: project_dir.to_path_buf(), source })?;
+        let source = file_source(project_dir.join("PklProject"));
+
+        let evaluator = manager.new_evaluator(Some(Self::preconfigured()))?;
+        let settings = EvaluatorSettings::decode(&evaluator.evaluate_expression_raw(&source, Some(&settings::project_expr()))?)?;
+        let project = decode_project(&evaluator.evaluate_expression::<String>(&source, PROJECT_EXPR)?)?;
+        evaluator.close()?;
+
+        // catch a missing or stale lock file here, the server's error doesn't name the dependency
+        let deps = match ProjectDeps::read(project_dir) {
+            Err(Error::Io { path, source }) if source.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::Project(format!("{} does not exist, run `pkl project resolve`", path.display())));
+            },
+            deps => deps?,
+        };
+        deps.check(&project)?;
+
+        let mut opts = settings.apply(Self::preconfigured(), project_dir).with_project_dir(project_dir.to_string_lossy());
+        opts.declared_project_dependency = ProjectDependencies::from_message(&deps.to_message(&project, project_dir)?);
+        Ok(opts)
+    }
+
+    /// Set the property `key`, read with `read("prop:key")`
+    pub fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.insert(key.into(), value.into());
+        self
+    }
+
+    /// Set the environment variable `key`, read with `read("env:key")`
+    pub fn with_env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    /// Add the environment of this process, skipping variables that aren't UTF-8
+    pub fn with_os_env(mut self) -> Self {
+        self.env.extend(os_env());
+        self
+    }
+
+    /// Add a directory or archive to the module path
+    pub fn with_module_path(mut self, path: impl Into<String>) -> Self {
+        self.module_paths.push(path.into());
+        self
+    }
+
+    /// Restrict `file:` modules and resources to `dir`
+    pub fn with_root_dir(mut self, dir: impl Into<String>) -> Self {
+        self.root_dir = dir.into();
+        self
+    }
+
+    /// Evaluate in the context of the project in `dir`
+    pub fn with_project_dir(mut self, dir: impl Into<String>) -> Self {
+        self.project_dir = dir.into();
+        self
+    }
+
+    /// Cache downloaded packages in `dir`
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = dir.into();
+        self
+    }
+
+    /// Cache downloaded packages in `~/.pkl/cache`, unchanged without a home directory
+    pub fn with_default_cache_dir(mut self) -> Self {
+        if let Some(dir) = default_cache_dir() {
+            self.cache_dir = dir;
+        }
+        self
+    }
+
+    /// Render `output.text` with `format` unless the module picks a renderer
+    pub fn with_output_format(mut self, format: impl Into<String>) -> Self {
+        self.output_format = format.into();
+        self
+    }
+
+    /// Abort evaluations that take longer than `seconds`
+    pub fn with_timeout_seconds(mut self, seconds: i64) -> Self {
+        self.timeout_seconds = Some(seconds);
+        self
+    }
+
+    /// Allow importing modules matching `pattern`
+    pub fn with_allowed_module(mut self, pattern: impl Into<String>) -> Self {
+        self.allowed_modules.push(pattern.into());
+        self
+    }
+
+    /// Allow the schemes of [`default_allowed_modules`]
+    pub fn with_default_allowed_modules(mut self) -> Self {
+        self.allowed_modules.extend(default_allowed_modules());
+        self
+    }
+
+    /// Allow reading resources matching `pattern`
+    pub fn with_allowed_resource(mut self, pattern: impl Into<String>) -> Self {
+        self.allowed_resources.push(pattern.into());
+        self
+    }
+
+    /// Allow the schemes of [`default_allowed_resources`]
+    pub fn with_default_allowed_resources(mut self) -> Self {
+        self.allowed_resources.extend(default_allowed_resources());
+        self
+    }
+
+    /// Serve modules with the scheme of `reader` from Rust
+    ///
+    /// The scheme is allowed as well, so modules can import it right away.
+    pub fn with_module_reader(mut self, reader: impl ModuleReaderImpl + 'static) -> Self {
+        let scheme = format!("{}:", reader.scheme());
+        if !self.allowed_modules.contains(&scheme) {
+            self.allowed_modules.push(scheme);
+        }
+        self.module_readers.push(Arc::new(reader));
+        self
+    }
+
+    /// Serve resources with the scheme of `reader` from Rust
+    ///
+    /// The scheme is allowed as well, so modules can read it right away.
+    pub fn with_resource_reader(mut self, reader: impl ResourceReaderImpl + 'static) -> Self {
+        let scheme = format!("{}:", reader.scheme());
+        if !self.allowed_resources.contains(&scheme) {
+            self.allowed_resources.push(scheme);
+        }
+        self.resource_readers.push(Arc::new(reader));
+        self
+    }
+
+    /// Send log messages from the evaluator to `logger`
+    pub fn with_logger(mut self, logger: impl Logger + 'static) -> Self {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// Build the message asking the server for an evaluator with these options
+    pub(crate) fn create_evaluator(&self, request_id: i64) -> CreateEvaluator {
+        CreateEvaluator {
+            request_id,
+            client_resource_readers: (!self.resource_readers.is_empty()).then(|| self.resource_readers.iter().map(|r| r.descriptor()).collect()),
+            client_module_readers: (!self.module_readers.is_empty()).then(|| self.module_readers.iter().map(|r| r.descriptor()).collect()),
+            module_paths: (!self.module_paths.is_empty()).then(|| self.module_paths.clone()),
+            env: Some(self.env.clone()),
+            properties: Some(self.properties.clone()),
+            output_format: (!self.output_format.is_empty()).then(|| self.output_format.clone()),
+            allowed_modules: Some(self.allowed_modules.clone()),
+            allowed_resources: Some(self.allowed_resources.clone()),
+            root_dir: (!self.root_dir.is_empty()).then(|| self.root_dir.clone()),
+            cache_dir: (!self.cache_dir.as_os_str().is_empty()).then(|| self.cache_dir.to_string_lossy().into_owned()),
+            project: self.project(),
+            timeout_seconds: self.timeout_seconds,
+        }
+    }
+
+    /// The project at `project_dir` along with its declared dependencies
+    fn project(&self) -> Option<ProjectOrDependency> {
+        if self.project_dir.is_empty() {
+            return None;
+        }
+
+        let project_file = PathBuf::from(&self.project_dir).join("PklProject");
+        let project_file_uri = Url::from_file_path(&project_file)
+            .map(String::from)
+            .unwrap_or_else(|()| format!("file://{}", project_file.display()));
+
+        Some(ProjectOrDependency {
+            package_uri: None,
+            r#type: "local".into(),
+            project_file_uri: Some(project_file_uri),
+            checksums: None,
+            dependencies: self.declared_project_dependency.to_message(),
+        })
+    }
 }
 
 #[derive(Default)]
-struct ProjectRemoteDependency {
-    package_uri: String, // TODO this should be a path
-    checksums: String, //TODO should this be unified with the msg_api::Checksums type?
+pub struct ProjectRemoteDependency {
+    pub package_uri: String, // TODO this should be a path
+    /// sha256 checksum of the package
+    pub checksums: String, //TODO should this be unified with the msg_api::Checksums type?
 }
 
 #[derive(Default)]
-struct ProjectLocalDependency {
-    package_uri: String,
-    project_file_uri: String,
-    dependencies: ProjectDependencies
+pub struct ProjectLocalDependency {
+    pub package_uri: String,
+    pub project_file_uri: String,
+    pub dependencies: ProjectDependencies
 }
 
 #[derive(Default)]
-struct ProjectDependencies {
-    local_dependencies: HashMap<String, ProjectLocalDependency>,
-    remote_dependencies: HashMap<String, ProjectRemoteDependency>,
+pub struct ProjectDependencies {
+    pub local_dependencies: HashMap<String, ProjectLocalDependency>,
+    pub remote_dependencies: HashMap<String, ProjectRemoteDependency>,
+}
+
+impl ProjectDependencies {
+    /// The dependencies of a project as loaded from `PklProject`
+    pub(crate) fn from_message(deps: &HashMap<String, ProjectOrDependency>) -> Self {
+        let mut result = ProjectDependencies::default();
+
+        for (name, dep) in deps {
+            let package_uri = dep.package_uri.clone().unwrap_or_default();
+            if dep.r#type == "remote" {
+                let checksums = dep.checksums.as_ref().map(|c| c.sha256.clone()).unwrap_or_default();
+                result.remote_dependencies.insert(name.clone(), ProjectRemoteDependency { package_uri, checksums });
+            } else {
+                result.local_dependencies.insert(name.clone(), ProjectLocalDependency {
+                    package_uri,
+                    project_file_uri: dep.project_file_uri.clone().unwrap_or_default(),
+                    dependencies: ProjectDependencies::from_message(&dep.dependencies),
+                });
+            }
+        }
+
+        result
+    }
+
+    /// The dependencies in the shape sent in `CreateEvaluator.project`
+    pub(crate) fn to_message(&self) -> HashMap<String, ProjectOrDependency> {
+        let local = self.local_dependencies.iter().map(|(name, dep)| {
+            (name.clone(), ProjectOrDependency {
+                package_uri: Some(dep.package_uri.clone()),
+                r#type: "local".into(),
+                project_file_uri: Some(dep.project_file_uri.clone()),
+                checksums: None,
+                dependencies: dep.dependencies.to_message(),
+            })
+        });
+
+        let remote = self.remote_dependencies.iter().map(|(name, dep)| {
+            (name.clone(), ProjectOrDependency {
+                package_uri: Some(dep.package_uri.clone()),
+                r#type: "remote".into(),
+                project_file_uri: None,
+                checksums: (!dep.checksums.is_empty()).then(|| Checksums { sha256: dep.checksums.clone() }),
+                dependencies: Default::default(),
+            })
+        });
+
+        local.chain(remote).collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::evaluator::{fake_server::{Action, FakeServer}, msg_api::outgoing::OutgoingMessage, project::ResolvedDependency};
+
     use super::*;
 
     #[test]
     fn default_options_test() {
         let defaults: EvaluatorOptions = Default::default();
 
-        defaults.logger.trace("hello, ".into(), "world".into());
+        defaults.logger.trace("hello, ", "world");
+    }
+
+    #[test]
+    fn test_presets() {
+        let preconfigured = EvaluatorOptions::preconfigured();
+        assert_eq!(preconfigured.allowed_modules, default_allowed_modules());
+        assert_eq!(preconfigured.allowed_resources, default_allowed_resources());
+        if let Some(home) = home_dir() {
+            assert_eq!(preconfigured.cache_dir, home.join(".pkl/cache"));
+        }
+
+        let hermetic = EvaluatorOptions::hermetic()
+            .with_property("name", "pigeon")
+            .with_module_path("/opt/pkl")
+            .with_root_dir("/work");
+        assert!(hermetic.env.is_empty());
+        assert!(hermetic.allowed_modules.iter().chain(&hermetic.allowed_resources).all(|s| !NETWORK_SCHEMES.contains(&s.as_str())));
+        assert!(hermetic.allowed_resources.contains(&"prop:".to_string()));
+
+        let msg = hermetic.create_evaluator(1);
+        assert_eq!(msg.properties.unwrap()["name"], "pigeon");
+        assert_eq!(msg.module_paths.unwrap(), ["/opt/pkl"]);
+        assert_eq!(msg.root_dir.as_deref(), Some("/work"));
+        assert!(msg.cache_dir.is_none());
+    }
+
+    #[test]
+    fn test_from_settings_file() {
+        let settings = EvaluatorSettings {
+            external_properties: Some((0x02, HashMap::from([("stage".to_string(), "prod".to_string())]))),
+            allowed_modules: Some((0x04, vec_of_strings!["pkl:", "file:"])),
+            module_path: Some((0x04, vec_of_strings!["lib", "/opt/pkl"])),
+            module_cache_dir: Some("cache".into()),
+            root_dir: Some("../config".into()),
+            timeout: Some(30),
+            ..Default::default()
+        };
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::respond_value((0x02, &settings))])
+            .start();
+        let manager = EvaluatorManager::with_executor(exec);
+
+        let opts = EvaluatorOptions::from_settings_file(&manager, "/home/me/.pkl/settings.pkl").expect("Failed to read the settings");
+        assert_eq!(opts.properties, HashMap::from([("stage".to_string(), "prod".to_string())]));
+        assert_eq!(opts.allowed_modules, ["pkl:", "file:"]);
+        assert_eq!(opts.allowed_resources, default_allowed_resources());
+        assert_eq!(opts.module_paths, ["/home/me/.pkl/lib", "/opt/pkl"]);
+        assert_eq!(opts.cache_dir, PathBuf::from("/home/me/.pkl/cache"));
+        assert_eq!(opts.root_dir, "/home/me/.pkl/../config");
+        assert_eq!(opts.timeout_seconds, Some(30));
+        assert_eq!(opts.env, os_env());
+
+        drop(manager);
+        let received = server.join();
+        let OutgoingMessage::Evaluate(msg) = &received[1] else { panic!("expected Evaluate") };
+        assert_eq!(msg.module_uri, "file:///home/me/.pkl/settings.pkl");
+        assert_eq!(msg.expr.as_deref(), Some(settings::settings_file_expr().as_str()));
+        assert!(matches!(&received[2], OutgoingMessage::CloseEvaluator(_)));
+    }
+
+    #[test]
+    fn test_from_project() {
+        let settings = EvaluatorSettings {
+            env: Some((0x02, HashMap::from([("STAGE".to_string(), "prod".to_string())]))),
+            allowed_resources: Some((0x04, vec_of_strings!["file:", "env:"])),
+            module_path: Some((0x04, vec_of_strings!["lib", "/opt/pkl"])),
+            no_cache: Some(true),
+            root_dir: Some("config".into()),
+            timeout: Some(60),
+            ..Default::default()
+        };
+        let project = r#"{"type": "local", "dependencies": {"birds": {"type": "remote", "packageUri": "package://example.com/birds@0.4.0"}}}"#;
+        let (exec, server) = FakeServer::new()
+            .evaluation([Action::respond_value((0x02, &settings))])
+            .evaluation([Action::respond_value(project)])
+            .evaluation([Action::respond_value((0x02, &settings))])
+            .evaluation([Action::respond_value(project)])
+            .start();
+        let manager = EvaluatorManager::with_executor(exec);
+
+        let dir = std::env::temp_dir().join(format!("pkl-project-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let work = dir.to_string_lossy();
+
+        let err = EvaluatorOptions::from_project(&manager, dir.join("missing")).err().expect("a missing project is an error");
+        assert!(matches!(&err, Error::Io { path, .. } if path.ends_with("missing")), "{err}");
+
+        // without a lock file the dependencies can't be resolved
+        let err = EvaluatorOptions::from_project(&manager, &dir).err().expect("a missing lock file is an error");
+        assert!(matches!(&err, Error::Project(x) if x.contains("PklProject.deps.json does not exist")), "{err}");
+
+        let sha256 = "8ee9e2c3".repeat(8);
+        let deps = ProjectDeps {
+            resolved_dependencies: [("package://example.com/birds@0".to_string(), ResolvedDependency::Remote {
+                uri: "projectpackage://example.com/birds@0.5.0".into(),
+                checksums: Checksums { sha256: sha256.clone() },
+            })].into(),
+            ..Default::default()
+        };
+        deps.write(&dir).unwrap();
+
+        let opts = EvaluatorOptions::from_project(&manager, &dir).expect("Failed to read the project settings");
+        assert_eq!(opts.env, HashMap::from([("STAGE".to_string(), "prod".to_string())]));
+        assert_eq!(opts.allowed_resources, ["file:", "env:"]);
+        assert_eq!(opts.allowed_modules, default_allowed_modules());
+        assert_eq!(opts.module_paths, [format!("{work}/lib"), "/opt/pkl".to_string()]);
+        assert_eq!(opts.cache_dir, PathBuf::new());
+        assert_eq!(opts.root_dir, format!("{work}/config"));
+        assert_eq!(opts.timeout_seconds, Some(60));
+        assert_eq!(opts.project_dir, work);
+        let birds = &opts.declared_project_dependency.remote_dependencies["birds"];
+        assert_eq!(birds.package_uri, "projectpackage://example.com/birds@0.5.0");
+        assert_eq!(birds.checksums, sha256);
+
+        drop(manager);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let OutgoingMessage::Evaluate(msg) = &server.join()[1] else { panic!("expected Evaluate") };
+        assert_eq!(msg.module_uri, format!("file://{work}/PklProject"));
+        assert!(msg.expr.as_deref().unwrap().starts_with("let (s = module.evaluatorSettings)"));
     }
 }
@@ -1,19 +1,27 @@
-use std::{process::{Command, Child, Stdio, ChildStdin, ChildStdout}, sync::{mpsc::{Sender, Receiver, channel, RecvError}, atomic::AtomicBool}, os::unix::process::CommandExt, io::{Write, BufReader, BufWriter, Read}, cmp::Reverse, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use std::{collections::HashMap, env, ffi::OsString, fmt, io::{self, Read, Write}, path::Path, process::{Command, Stdio}, sync::{mpsc::{channel, Sender}, Arc, Mutex, Weak}, thread};
 
-use serde::Serialize;
+use crate::Error;
 
-use super::msg_api::{incoming::*, outgoing::*, code::*};
+use super::{evaluator_options::EvaluatorOptions, logger::Logger, msg_api::{frame::FrameError, incoming::*, outgoing::*}, reader::{self, ModuleReaderImpl, ResourceReaderImpl}, transport::{ProcessTransport, Transport}};
+
+/// Environment variable holding the command used to launch pkl,
+/// e.g. `PKL_EXEC="java -jar pkl.jar"`
+pub const PKL_EXEC_ENV: &str = "PKL_EXEC";
 
 /// A struct that handles the communication with the pkl evaluator
 ///
-/// This is essentially a wrapper to hold and abstract the
-/// spawned pkl child process
+/// This is essentially a wrapper to hold and abstract the connection to
+/// the pkl server. By default this is a spawned pkl child process, but any
+/// [`Transport`] can be used through [`Executor::with_transport`].
 ///
 /// # Diagram
 ///
-/// When we instantiate an EvaluatorManagerExec, we spawn a pkl process
-/// and then communicate to it via message passing with two threads
-/// handling the incoming and outgoing messages respectively.
+/// When we instantiate an Executor, we spawn a pkl process and then
+/// communicate with it via message passing. Outgoing messages are written
+/// by the caller, while a background reader thread decodes every incoming
+/// frame. Replies are routed to the waiting caller by `request_id`, while
+/// `Log` messages and reader callbacks are handled right on the reader
+/// thread with the readers and logger of the evaluator they name.
 /// ```ignore
 ///   pkl-rust           pkl
 ///      |  get version
@@ -25,6 +33,8 @@ use super::msg_api::{incoming::*, outgoing::*, code::*};
 ///      |<---------------|
 ///      |                |
 ///      | pass messages  |
+///      |--------------->| // written by the caller
+///      |<---------------| // decoded by the reader thread
 ///      |  ...           |
 ///      |                |
 ///      | close server   |
@@ -35,152 +45,419 @@ use super::msg_api::{incoming::*, outgoing::*, code::*};
 ///      ...
 ///      user program
 /// ```
-///
-#[derive(Debug)]
 pub struct Executor {
-    child_process: Child,
+    transport: Mutex<Box<dyn Transport>>,
+    /// Output of `pkl --version`, empty if the server was not spawned by us
     pub version: String,
+    /// Command the server was spawned with, empty if it was not spawned by us
     pub pkl_command: Vec<String>,
-    pub child_out: Option<ChildStdout>,
-    pub child_in: Option<ChildStdin>,
+    writer: SharedWriter,
+    pending_requests: PendingRequests,
+    evaluators: Evaluators,
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("version", &self.version)
+            .field("pkl_command", &self.pkl_command)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a waiting caller receives, the response or why it will never come
+pub type Received = Result<IncomingMessage, Error>;
+
+/// Callers waiting on messages from the pkl server, keyed by request id
+pub type PendingRequests<S = Sender<Received>> = Arc<Mutex<HashMap<i64, S>>>;
+
+/// Every live evaluator, keyed by evaluator id
+pub(crate) type Evaluators<S = Sender<Received>> = Arc<Mutex<HashMap<i64, Registration<S>>>>;
+
+/// The writing half of the connection, shared by callers and the reader thread
+///
+/// The reader takes it away once nothing more can be read from the server.
+type SharedWriter = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
+
+/// What the reader needs to know about a live evaluator
+#[derive(Debug)]
+pub(crate) struct Registration<S> {
+    /// Evaluations waiting for their response
+    pub pending_requests: PendingRequests<S>,
+    pub callbacks: Arc<Callbacks>,
+}
+
+/// Answers the server's callbacks for one evaluator
+///
+/// Reader requests are answered and `Log` messages delivered by the reader
+/// as they arrive, so they reach the right evaluator no matter how many of
+/// its evaluations are in flight, or whether any is at all. Readers run on
+/// the reader thread and must not evaluate on the same executor.
+pub(crate) struct Callbacks {
+    module_readers: Vec<Arc<dyn ModuleReaderImpl>>,
+    resource_readers: Vec<Arc<dyn ResourceReaderImpl>>,
+    logger: Arc<dyn Logger>,
+}
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("module_readers", &self.module_readers.iter().map(|r| r.scheme()).collect::<Vec<_>>())
+            .field("resource_readers", &self.resource_readers.iter().map(|r| r.scheme()).collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Callbacks {
+    /// The readers and logger configured in `opts`
+    pub(crate) fn new(opts: &EvaluatorOptions) -> Self {
+        Self {
+            module_readers: opts.module_readers.clone(),
+            resource_readers: opts.resource_readers.clone(),
+            logger: opts.logger.clone(),
+        }
+    }
+
+    /// Handle a callback for `evaluator_id`, returning the reply to send back
+    ///
+    /// `Log` messages are delivered to the logger and need no reply.
+    fn answer(&self, evaluator_id: i64, msg: IncomingMessage) -> Option<OutgoingMessage> {
+        match msg {
+            IncomingMessage::ReadResource(x) => Some(reader::read_resource(&self.resource_readers, evaluator_id, x)),
+            IncomingMessage::ReadModule(x) => Some(reader::read_module(&self.module_readers, evaluator_id, x)),
+            IncomingMessage::ListResources(x) => Some(reader::list_resources(&self.resource_readers, evaluator_id, x)),
+            IncomingMessage::ListModules(x) => Some(reader::list_modules(&self.module_readers, evaluator_id, x)),
+            IncomingMessage::Log(x) => {
+                self.logger.log(&x);
+                None
+            },
+            IncomingMessage::CreateEvaluatorResponse(_) | IncomingMessage::EvaluateResponse(_) => None,
+        }
+    }
+}
+
+/// A channel the reader can hand decoded messages to
+pub(crate) trait MessageSender: Clone {
+    fn deliver(&self, msg: Received);
+}
+
+impl MessageSender for Sender<Received> {
+    fn deliver(&self, msg: Received) {
+        // the receiver is gone if the caller gave up waiting
+        let _ = self.send(msg);
+    }
 }
 
-impl Default for Executor {
-    fn default() -> Self {
-        //TODO need to get the pkl command from the user? or at least search the system PATH
-        let pkl_command = vec!["/home/stormblessed/software/pkl".to_string(), "server".to_string()];
+/// Builder for an [`Executor`]
+///
+/// The pkl command is resolved in the following order:
+/// 1. the command given to [`ExecutorBuilder::pkl_command`]
+/// 2. the `PKL_EXEC` environment variable, split on whitespace
+/// 3. a `pkl` executable found on the `PATH`
+///
+/// The command may be several words long (`java -jar pkl.jar`), and
+/// `server` is appended to it unless it is already the last word.
+///
+/// # Example
+///
+/// ```no_run
+/// use pkl_bind::evaluator::executor::Executor;
+///
+/// let exec = Executor::builder()
+///     .pkl_command(["java", "-jar", "pkl.jar", "server"])
+///     .build()
+///     .expect("failed to start pkl");
+/// ```
+#[derive(Debug, Default)]
+pub struct ExecutorBuilder {
+    pkl_command: Option<Vec<String>>,
+}
+
+impl ExecutorBuilder {
+    /// Explicitly set the command used to launch pkl
+    pub fn pkl_command<I, S>(mut self, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.pkl_command = Some(command.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub(crate) fn into_pkl_command(self) -> Option<Vec<String>> {
+        self.pkl_command
+    }
+
+    /// Locate pkl, check its version and spawn the server process
+    pub fn build(self) -> Result<Executor, Error> {
+        let pkl_command = find_pkl_command(self.into_pkl_command())?;
+        let (program, args) = pkl_command.split_first().ok_or(Error::PklNotFound)?;
 
         // Checking the version of pkl on the host
-        let version_check = Command::new(pkl_command.first().expect("Lol, well that sucked").to_string())
+        let version_check = Command::new(program)
+                                .args(&args[..args.len() - 1])
                                 .arg("--version")
                                 .stdout(Stdio::piped())
-                                .spawn()
-                                .expect("Failed to start pkl process");
-
-        let v_out = version_check.wait_with_output().expect("Failed to get output").stdout;
-        let version: String = String::from_utf8(v_out.to_vec()).expect("Failed to convert output to string");
+                                .output()
+                                .map_err(Error::Spawn)?;
+        let version = String::from_utf8_lossy(&version_check.stdout).trim().to_string();
 
         // Init the actual child process
-        let mut child_process = Command::new(pkl_command.first().expect("no pkl command given").to_string())
-                                .args(pkl_command.split_first().expect("pkl_command vector is empty!").1)
-                                .stdin(Stdio::piped())
-                                .stdout(Stdio::piped())
-                                .stderr(Stdio::piped())
-                                .spawn()
-                                .expect("failed to spawn pkl server process");
+        let transport = ProcessTransport::spawn(&pkl_command).map_err(Error::Spawn)?;
 
-        // Thread spawning
-        let child_in: ChildStdin = child_process.stdin.take().expect("Failed to open stdout");
-        let child_out: ChildStdout = child_process.stdout.take().expect("Failed to open stdin");
-        // let _ = spawn_write_thread(t_recv, kill_recv_w, child_in);
-        // let _ = spawn_read_thread(t_send, kill_recv_r, child_out);
+        let mut exec = Executor::with_transport(transport)?;
+        exec.version = version;
+        exec.pkl_command = pkl_command;
+        Ok(exec)
+    }
+}
 
-        Self {
-            version,
-            pkl_command,
-            child_process,
-            child_in: Some(child_in),
-            child_out: Some(child_out),
+/// Resolve the command used to launch the pkl server
+///
+/// See [`ExecutorBuilder`] for the lookup order.
+pub fn find_pkl_command(explicit: Option<Vec<String>>) -> Result<Vec<String>, Error> {
+    resolve_pkl_command(explicit, env::var(PKL_EXEC_ENV).ok(), env::var_os("PATH"))
+}
+
+fn resolve_pkl_command(explicit: Option<Vec<String>>, pkl_exec: Option<String>, path: Option<OsString>) -> Result<Vec<String>, Error> {
+    let from_env = pkl_exec.map(|cmd| cmd.split_whitespace().map(String::from).collect::<Vec<_>>());
+
+    let mut command = match explicit.or(from_env).filter(|cmd| !cmd.is_empty()) {
+        Some(cmd) => cmd,
+        None => {
+            let found = path.iter()
+                .flat_map(env::split_paths)
+                .map(|dir| dir.join("pkl"))
+                .find(|candidate| is_executable(candidate))
+                .ok_or(Error::PklNotFound)?;
+            vec![found.to_string_lossy().into_owned()]
         }
+    };
+
+    if command.last().map(String::as_str) != Some("server") {
+        command.push("server".into());
     }
+
+    Ok(command)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
 }
 
 impl Executor {
+    /// Locate pkl and spawn a server using the default lookup order
+    pub fn new() -> Result<Self, Error> {
+        ExecutorBuilder::default().build()
+    }
+
+    /// Create a builder to configure how pkl is launched
+    pub fn builder() -> ExecutorBuilder {
+        ExecutorBuilder::default()
+    }
+
+    /// Communicate with a pkl server over an arbitrary transport
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use pkl_bind::evaluator::{executor::Executor, transport::UnixSocketTransport};
+    ///
+    /// let transport = UnixSocketTransport::connect("/run/pkl.sock").expect("failed to connect");
+    /// let exec = Executor::with_transport(transport).expect("failed to set up transport");
+    /// ```
+    pub fn with_transport(mut transport: impl Transport + 'static) -> Result<Self, Error> {
+        let reader = transport.reader().map_err(Error::Transport)?;
+        let writer = transport.writer().map_err(Error::Transport)?;
+
+        let pending_requests: PendingRequests = Default::default();
+        let evaluators: Evaluators = Default::default();
+
+        let writer: SharedWriter = Arc::new(Mutex::new(Some(writer)));
+
+        // the reader must not keep the connection open once the executor is dropped
+        let (pending, evals, replies) = (pending_requests.clone(), evaluators.clone(), Arc::downgrade(&writer));
+        thread::Builder::new()
+            .name("pkl-reader".into())
+            .spawn(move || read_loop(reader, replies, pending, evals))
+            .map_err(Error::Transport)?;
+
+        Ok(Self {
+            transport: Mutex::new(Box::new(transport)),
+            version: Default::default(),
+            pkl_command: Default::default(),
+            writer,
+            pending_requests,
+            evaluators,
+        })
+    }
+
     /// Internal method to kill the evaluator
     fn deinit(&mut self) -> Result<(), std::io::Error> {
         //TODO this should also be logged
-        // self.killer_recv.send(vec![0xff, 0xff]).expect("Failed to send kill signal to pkl reader");
-        // self.killer_wrtr.send(vec![0xff, 0xff]).expect("Failed to send kill signal to pkl writer");
-
-        self.child_process.kill()
+        self.transport.get_mut().expect("transport lock poisoned").close()
     }
 
     // REVIEW: is it possible to make these async?
-    pub(crate) fn send(&mut self, msg: OutgoingMessage) {
-        let message: Vec<u8> = pack_message(msg).expect("Failed to pack message");
+    pub(crate) fn send(&self, msg: OutgoingMessage) -> Result<(), Error> {
+        let mut sender = self.writer.lock().expect("pkl writer lock poisoned");
+        let out = sender.as_mut().ok_or(Error::Closed)?;
+
+        write_message(out, msg)
+    }
 
-        let mut sender = self.child_in.take().expect("Failed to take");
+    /// Send a request and block until the server replies to it
+    ///
+    /// Only messages carrying a request id (`CreateEvaluator` and
+    /// `Evaluate`) receive a reply.
+    pub(crate) fn senrec(&self, msg: OutgoingMessage) -> Result<IncomingMessage, Error> {
+        let request_id = msg.request_id().ok_or_else(|| Error::Protocol("message does not expect a response".into()))?;
+        let (send, recv) = channel();
 
-        sender.write_all(&message).expect("Failed to send message");
-        // println!("Sent message: {:?}", msg);
+        self.pending_requests.lock().expect("pending requests lock poisoned").insert(request_id, send);
 
-        self.child_in = Some(sender);
+        // the reader thread drops the sender once the server goes away
+        let resp = self.send(msg).and_then(|()| recv.recv().map_err(|_| Error::Closed)?);
+        self.pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id);
+        resp
     }
 
-    pub(crate) fn senrec(&mut self, msg: OutgoingMessage) -> Result<IncomingMessage, RecvError> {
-        self.send(msg);
-
-        let mut buf = [0u8; 2];
-        let prefix: MessageCode;
-        // now the fun part
-        let mut out = self.child_out.take().expect("Failed to take"); // need to take here to use in the match
-        out.read_exact(&mut buf).expect("Failed to read buffer");
+    /// Route responses for `evaluator_id` to the given pending requests and answer its callbacks
+    pub(crate) fn register_evaluator(&self, evaluator_id: i64, pending_requests: PendingRequests, callbacks: Callbacks) {
+        let registration = Registration { pending_requests, callbacks: Arc::new(callbacks) };
+        self.evaluators.lock().expect("evaluators lock poisoned").insert(evaluator_id, registration);
+    }
 
-        prefix = MessageCode::try_from(buf[1]).expect("Failed to convert to MessageCode");
+    /// Stop routing messages for `evaluator_id`
+    pub(crate) fn unregister_evaluator(&self, evaluator_id: i64) {
+        self.evaluators.lock().expect("evaluators lock poisoned").remove(&evaluator_id);
+    }
+}
 
-        let mut value: Option<IncomingMessage> = None;
-        // TODO not very DRY, but this might be the most idiomatic way to use serde
-        match prefix {
-            MessageCode::NewEvaluatorResponse => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix); //TODO Switch to logging
-                match rmp_serde::from_read::<_, CreateEvaluatorResponse>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::CreateEvaluatorResponse(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
-                }
-            },
-            MessageCode::EvaluateResponse => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix);
-                match rmp_serde::from_read::<_, EvaluateResponse>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::EvaluateResponse(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
-                }
-            },
-            MessageCode::EvaluateReadModule => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix);
-                match rmp_serde::from_read::<_, ReadModule>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::ReadModule(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
-                }
-            },
-            MessageCode::ListResourcesRequest => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix);
-                match rmp_serde::from_read::<_, ListResources>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::ListResources(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
-                }
-            },
-            MessageCode::ListModulesRequest => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix);
-                match rmp_serde::from_read::<_, ListModules>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::ListModules(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
-                }
-            },
-            MessageCode::EvaluateLog => {
-                println!("Matched new evaluator, Code: {:02X?}", prefix);
-                match rmp_serde::from_read::<_, Log>(&mut out) {
-                    Ok(msg) => value = Some(IncomingMessage::Log(msg)),
-                    Err(err) => eprintln!("Error decoding the message: {}", err),
+/// Body of the reader thread
+///
+/// Decodes frames until the server closes its stdout or the stream breaks
+/// down, then disconnects so that blocked callers are woken up with an error.
+fn read_loop(mut out: Box<dyn Read + Send>, writer: Weak<Mutex<Option<Box<dyn Write + Send>>>>, pending_requests: PendingRequests, evaluators: Evaluators) {
+    let failure = loop {
+        match decode_message(&mut out) {
+            Decoded::Message(msg) => {
+                let reply = dispatch(msg, &pending_requests, &evaluators);
+                if let Some((reply, writer)) = reply.zip(writer.upgrade()) {
+                    if let Some(out) = writer.lock().expect("pkl writer lock poisoned").as_mut() {
+                        // a broken connection also ends the read side
+                        let _ = write_message(out, reply);
+                    }
                 }
             },
-            _ => {
-                return Err(RecvError)
-            }
+            Decoded::Skipped { error, ids } => skip(error, ids, &pending_requests, &evaluators),
+            Decoded::Failed(error) => break Some(error),
+            Decoded::Incomplete => break None,
         }
+    };
 
-        self.child_out = Some(out);
+    // nothing sent from now on could be answered
+    if let Some(writer) = writer.upgrade() {
+        writer.lock().expect("pkl writer lock poisoned").take();
+    }
+    disconnect(failure, &pending_requests, &evaluators);
+}
 
-        return Ok(value.expect("Failed to retrieve value"));
+/// Hand an incoming message to whoever is waiting for it
+///
+/// Replies are routed by request id. `Log` and reader callbacks are answered
+/// with the [`Callbacks`] of the evaluator they name, returning the reply
+/// the caller has to send back. Messages for unknown evaluators and replies
+/// nobody waits for are dropped.
+pub(crate) fn dispatch<S: MessageSender>(msg: IncomingMessage, pending_requests: &PendingRequests<S>, evaluators: &Evaluators<S>) -> Option<OutgoingMessage> {
+    let target = match &msg {
+        IncomingMessage::CreateEvaluatorResponse(resp) => {
+            pending_requests.lock().expect("pending requests lock poisoned").remove(&resp.request_id)
+        },
+        IncomingMessage::EvaluateResponse(resp) => {
+            let evaluator = evaluators.lock().expect("evaluators lock poisoned")
+                .get(&resp.evaluator_id)
+                .map(|registration| registration.pending_requests.clone());
+            evaluator
+                .and_then(|pending| pending.lock().expect("pending requests lock poisoned").remove(&resp.request_id))
+                .or_else(|| pending_requests.lock().expect("pending requests lock poisoned").remove(&resp.request_id))
+        },
+        callback => {
+            let evaluator_id = callback.evaluator_id()?;
+            let callbacks = evaluators.lock().expect("evaluators lock poisoned")
+                .get(&evaluator_id)
+                .map(|registration| registration.callbacks.clone())?;
+            // the lock is released, readers may take their time
+            return callbacks.answer(evaluator_id, msg);
+        },
+    };
+
+    if let Some(sender) = target {
+        sender.deliver(Ok(msg));
+    }
+    None
+}
+
+/// Report a message from the server that could not be decoded
+///
+/// The caller waiting for it gets the error. Otherwise it is logged as a
+/// warning by the evaluator it was meant for, or by every evaluator if that
+/// can't be made out.
+pub(crate) fn skip<S: MessageSender>(error: FrameError, ids: MessageIds, pending_requests: &PendingRequests<S>, evaluators: &Evaluators<S>) {
+    let message = format!("skipped a message from the pkl server: {error}");
+
+    let waiting = ids.request_id.and_then(|request_id| {
+        pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id).or_else(|| {
+            evaluators.lock().expect("evaluators lock poisoned").values()
+                .find_map(|registration| registration.pending_requests.lock().expect("pending requests lock poisoned").remove(&request_id))
+        })
+    });
+    if let Some(sender) = waiting {
+        return sender.deliver(Err(Error::Protocol(message)));
     }
 
-    // fn send(&self, msg: &impl Serialize, t: OutgoingMessage) {
-    //     let message = pack_message(msg, t).expect("Could not determine message type, failed to serialize");
-    //     self.sender.send(message).expect("Failed to send message to pkl");
-    // }
+    let loggers: Vec<_> = {
+        let evaluators = evaluators.lock().expect("evaluators lock poisoned");
+        match ids.evaluator_id.and_then(|id| evaluators.get(&id)) {
+            Some(registration) => vec![registration.callbacks.clone()],
+            None => evaluators.values().map(|registration| registration.callbacks.clone()).collect(),
+        }
+    };
+    for callbacks in loggers {
+        // there is no module to point at
+        callbacks.logger.warn(&message, "");
+    }
+}
+
+/// Wake every waiting caller once nothing more can be read from the server
+///
+/// Callers get the `failure` that broke the stream, as [`Error::Transport`]
+/// if reading failed, or see the connection closed if the server simply went
+/// away.
+pub(crate) fn disconnect<S: MessageSender>(failure: Option<FrameError>, pending_requests: &PendingRequests<S>, evaluators: &Evaluators<S>) {
+    let mut waiting: Vec<S> = pending_requests.lock().expect("pending requests lock poisoned").drain().map(|(_, sender)| sender).collect();
+    for (_, registration) in evaluators.lock().expect("evaluators lock poisoned").drain() {
+        waiting.extend(registration.pending_requests.lock().expect("pending requests lock poisoned").drain().map(|(_, sender)| sender));
+    }
 
-    // fn recv(&self) -> Result<IncomingMessage, RecvError> {
-    //     self.receiver.recv()
-    // }
+    if let Some(failure) = failure {
+        for sender in waiting {
+            let error = match &failure {
+                // io::Error can't be cloned, every caller gets its kind and message
+                FrameError::Io(err) => Error::Transport(io::Error::new(err.kind(), err.to_string())),
+                failure => Error::Protocol(format!("lost track of the messages from the pkl server: {failure}")),
+            };
+            sender.deliver(Err(error));
+        }
+    }
 }
 
 impl Drop for Executor {
@@ -191,8 +468,18 @@ impl Drop for Executor {
 
 #[cfg(test)]
 mod tests {
+    use crate::evaluator::{fake_server::FakeServer, logger::NoopLogger, reader::ModuleReaderImpl, transport::{MemoryTransport, UnixSocketTransport}};
+
     use super::*;
 
+    /// Registers evaluator `id` with the default readers and logger
+    fn register(evaluators: &Evaluators, id: i64, opts: &EvaluatorOptions) -> PendingRequests {
+        let pending_requests: PendingRequests = Default::default();
+        let registration = Registration { pending_requests: pending_requests.clone(), callbacks: Arc::new(Callbacks::new(opts)) };
+        evaluators.lock().unwrap().insert(id, registration);
+        pending_requests
+    }
+
     #[allow(dead_code)]
     fn print_binary(vec: &[u8]) {
         print!("Binary       : ");
@@ -206,9 +493,7 @@ mod tests {
 
     #[test]
     fn test_regular_send() {
-        let mut eval: Executor = Executor::default();
-
-        let mut r = [0u8;2];
+        let (eval, server) = FakeServer::new().evaluator(-135901).start();
 
         //TODO extract these as constants
         let allowed_modules: Vec<String> = vec!["pkl:".into(), "repl:".into(), "file:".into(), "customfs:".into()];
@@ -234,20 +519,20 @@ mod tests {
             timeout_seconds: None,
         };
 
-        let test1 = pack_message(OutgoingMessage::CreateEvaluator(create_eval)).expect("Failed to pack");
+        let (send, recv) = channel();
+        eval.pending_requests.lock().unwrap().insert(135, send);
 
-        let _ = eval.child_in.take().unwrap().write(&test1.to_vec());
-        // println!("Wrote message: {:?}", &test1.to_vec());
-        // print_binary(&test1.to_vec());
-        let a = eval.child_out.take().unwrap().read_exact(&mut r);
-        // print_binary(&r);
-        assert!(a.is_ok());
-        assert!(r[1] == 33);
+        eval.send(OutgoingMessage::CreateEvaluator(create_eval)).expect("Failed to send");
+        let a = recv.recv();
+        assert!(matches!(a, Ok(Ok(IncomingMessage::CreateEvaluatorResponse(_)))));
+
+        drop(eval);
+        assert_eq!(server.join().len(), 1);
     }
 
     #[test]
     fn test_senrec() {
-        let mut eval = Executor::default();
+        let (eval, _server) = FakeServer::new().evaluator(-135901).start();
 
         let allowed_modules: Vec<String> = vec!["pkl:".into(), "repl:".into(), "file:".into(), "customfs:".into()];
         let resource_reader = vec![ResourceReader {
@@ -277,6 +562,277 @@ mod tests {
 
         let result = eval.senrec(OutgoingMessage::CreateEvaluator(create_eval)).expect("Failed to accept");
         println!("Received evaluator response: {:?}", result);
+        assert_eq!(result.evaluator_id(), Some(-135901));
+    }
+
+    /// Plays the server side of a single `CreateEvaluator` exchange
+    fn answer_create_evaluator(mut input: impl Read, mut output: impl Write) {
+        #[derive(serde::Deserialize)]
+        struct Request {
+            #[serde(rename = "requestId")]
+            request_id: i64,
+        }
+
+        let (code, req): (u8, Request) = rmp_serde::from_read(&mut input).expect("Failed to decode request");
+        assert_eq!(code, 0x20);
+        assert_eq!(req.request_id, 135);
+
+        // [0x21, {requestId: 135, evaluatorId: -135901, error: null}]
+        output.write_all(&[0x92, 0x21, 0x83, 0xA9, 0x72, 0x65, 0x71, 0x75, 0x65,
+                           0x73, 0x74, 0x49, 0x64, 0xCC, 0x87, 0xAB, 0x65, 0x76,
+                           0x61, 0x6C, 0x75, 0x61, 0x74, 0x6F, 0x72, 0x49, 0x64,
+                           0xD2, 0xFF, 0xFD, 0xED, 0x23, 0xA5, 0x65, 0x72, 0x72,
+                           0x6F, 0x72, 0xC0]).unwrap();
+    }
+
+    fn create_evaluator_request() -> OutgoingMessage {
+        OutgoingMessage::CreateEvaluator(CreateEvaluator {
+            request_id: 135,
+            client_resource_readers: None,
+            client_module_readers: None,
+            module_paths: None,
+            env: None,
+            properties: None,
+            output_format: None,
+            allowed_modules: None,
+            allowed_resources: None,
+            root_dir: None,
+            cache_dir: None,
+            project: None,
+            timeout_seconds: None,
+        })
+    }
+
+    #[test]
+    fn test_senrec_over_memory_transport() {
+        let (client, server) = MemoryTransport::duplex();
+        let (input, output) = server.split();
+        let server = thread::spawn(move || answer_create_evaluator(input, output));
+
+        let exec = Executor::with_transport(client).expect("Failed to set up transport");
+        let resp = exec.senrec(create_evaluator_request()).expect("Failed to receive response");
+
+        assert_eq!(resp.evaluator_id(), Some(-135901));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_senrec_over_unix_socket() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let output = server.try_clone().unwrap();
+        let server = thread::spawn(move || answer_create_evaluator(server, output));
+
+        let exec = Executor::with_transport(UnixSocketTransport::from_stream(client)).expect("Failed to set up transport");
+        let resp = exec.senrec(create_evaluator_request()).expect("Failed to receive response");
+
+        assert_eq!(resp.evaluator_id(), Some(-135901));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_dispatch_by_request_id() {
+        let pending: PendingRequests = Default::default();
+        let evaluators: Evaluators = Default::default();
+        let eval_pending = register(&evaluators, 7, &EvaluatorOptions::default());
+
+        let (create_send, create_recv) = channel();
+        let (first_send, first_recv) = channel();
+        let (second_send, second_recv) = channel();
+        pending.lock().unwrap().insert(1, create_send);
+        eval_pending.lock().unwrap().insert(2, first_send);
+        eval_pending.lock().unwrap().insert(3, second_send);
+
+        let response = |request_id| IncomingMessage::EvaluateResponse(EvaluateResponse {
+            request_id,
+            evaluator_id: 7,
+            result: Some(vec![request_id as u8]),
+            error: None,
+        });
+
+        // replies arrive out of order
+        dispatch(response(3), &pending, &evaluators);
+        dispatch(IncomingMessage::CreateEvaluatorResponse(CreateEvaluatorResponse {
+            request_id: 1,
+            evaluator_id: Some(7),
+            error: None,
+        }), &pending, &evaluators);
+        dispatch(response(2), &pending, &evaluators);
+
+        assert_eq!(create_recv.recv().unwrap().unwrap().evaluator_id(), Some(7));
+        assert_eq!(first_recv.recv().unwrap().unwrap().request_id(), Some(2));
+        assert_eq!(second_recv.recv().unwrap().unwrap().request_id(), Some(3));
+        assert!(pending.lock().unwrap().is_empty());
+        assert!(eval_pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_callbacks_by_evaluator_id() {
+        #[derive(Debug)]
+        struct Named(&'static str);
+
+        impl ModuleReaderImpl for Named {
+            fn scheme(&self) -> &str { "named" }
+            fn is_local(&self) -> bool { true }
+            fn has_hierarchical_uris(&self) -> bool { false }
+            fn is_globbable(&self) -> bool { false }
+            fn read(&self, _uri: &url::Url) -> std::io::Result<String> { Ok(self.0.into()) }
+            fn list(&self, _uri: &url::Url) -> std::io::Result<Vec<PathElement>> { Ok(vec![]) }
+        }
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Logger for Arc<Recorder> {
+            fn trace(&self, message: &str, _frame_uri: &str) { self.0.lock().unwrap().push(message.into()); }
+            fn warn(&self, message: &str, _frame_uri: &str) { self.0.lock().unwrap().push(message.into()); }
+        }
+
+        let pending: PendingRequests = Default::default();
+        let evaluators: Evaluators = Default::default();
+        let logged = Arc::new(Recorder::default());
+        let seven = register(&evaluators, 7, &EvaluatorOptions::default().with_module_reader(Named("seven")).with_logger(logged.clone()));
+        register(&evaluators, 9, &EvaluatorOptions::default().with_module_reader(Named("nine")).with_logger(NoopLogger));
+
+        // two evaluations of 7 are waiting, neither sees the callbacks
+        let (first_send, first_recv) = channel();
+        let (second_send, second_recv) = channel();
+        seven.lock().unwrap().insert(2, first_send);
+        seven.lock().unwrap().insert(3, second_send);
+
+        let read = |evaluator_id| IncomingMessage::ReadModule(ReadModule { request_id: 40 + evaluator_id, evaluator_id, uri: "named:/a.pkl".into() });
+        let Some(OutgoingMessage::ReadModuleResponse(nine)) = dispatch(read(9), &pending, &evaluators) else { panic!("no reply for 9") };
+        let Some(OutgoingMessage::ReadModuleResponse(seven_reply)) = dispatch(read(7), &pending, &evaluators) else { panic!("no reply for 7") };
+        assert_eq!((nine.request_id, nine.evaluator_id, nine.contents.as_deref()), (49, 9, Some("nine")));
+        assert_eq!((seven_reply.request_id, seven_reply.evaluator_id, seven_reply.contents.as_deref()), (47, 7, Some("seven")));
+
+        let log = |evaluator_id| IncomingMessage::Log(Log {
+            evaluator_id,
+            level: 0,
+            message: format!("hello {evaluator_id}"),
+            frame_uri: "repl:text".into(),
+        });
+
+        assert!(dispatch(log(7), &pending, &evaluators).is_none());
+        assert!(dispatch(log(8), &pending, &evaluators).is_none()); // no such evaluator, dropped
+        assert_eq!(*logged.0.lock().unwrap(), ["hello 7"]);
+
+        // logs are delivered even when nothing is being evaluated
+        seven.lock().unwrap().clear();
+        dispatch(log(7), &pending, &evaluators);
+        assert_eq!(logged.0.lock().unwrap().len(), 2);
+
+        assert!(first_recv.try_recv().is_err());
+        assert!(second_recv.try_recv().is_err());
     }
 
+    #[test]
+    fn test_skipped_messages_are_reported() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Logger for Arc<Recorder> {
+            fn trace(&self, _message: &str, _frame_uri: &str) {}
+            fn warn(&self, message: &str, _frame_uri: &str) { self.0.lock().unwrap().push(message.into()); }
+        }
+
+        let pending: PendingRequests = Default::default();
+        let evaluators: Evaluators = Default::default();
+        let (seven_log, nine_log) = (Arc::new(Recorder::default()), Arc::new(Recorder::default()));
+        let seven = register(&evaluators, 7, &EvaluatorOptions::default().with_logger(seven_log.clone()));
+        register(&evaluators, 9, &EvaluatorOptions::default().with_logger(nine_log.clone()));
+
+        let (send, recv) = channel();
+        seven.lock().unwrap().insert(2, send);
+
+        let ids = |request_id, evaluator_id| MessageIds { request_id, evaluator_id };
+        // the caller waiting for the message is told
+        skip(FrameError::UnknownCode(0x7F), ids(Some(2), Some(7)), &pending, &evaluators);
+        assert!(matches!(recv.recv().unwrap(), Err(Error::Protocol(ref x)) if x.contains("0x7F")));
+        assert!(seven.lock().unwrap().is_empty());
+
+        // otherwise the evaluator it was meant for, or every evaluator
+        skip(FrameError::UnknownCode(0x7E), ids(None, Some(9)), &pending, &evaluators);
+        skip(FrameError::Envelope("expected an array"), ids(None, None), &pending, &evaluators);
+        assert_eq!(seven_log.0.lock().unwrap().len(), 1);
+        assert_eq!(nine_log.0.lock().unwrap().len(), 2);
+        assert!(nine_log.0.lock().unwrap()[0].contains("0x7E"));
+    }
+
+    #[test]
+    fn test_oversized_message_disconnects() {
+        let (client, server) = MemoryTransport::duplex();
+        let (mut input, mut output) = server.split();
+        let exec = Executor::with_transport(client).expect("Failed to set up transport");
+
+        let server = thread::spawn(move || {
+            let _: (u8, serde::de::IgnoredAny) = rmp_serde::from_read(&mut input).expect("Failed to decode request");
+            // [0x21, {requestId: <str32 of 1 GiB>...}], the contents never follow
+            output.write_all(&[0x92, 0x21, 0x81, 0xA9, b'r', b'e', b'q', b'u', b'e', b's', b't', b'I', b'd', 0xDB, 0x40, 0x00, 0x00, 0x00]).unwrap();
+            input
+        });
+
+        let err = exec.senrec(create_evaluator_request()).unwrap_err();
+        assert!(matches!(err, Error::Protocol(ref x) if x.contains("exceeds the limit")), "{err}");
+        assert!(matches!(exec.senrec(create_evaluator_request()), Err(Error::Closed)));
+
+        // the server sees the connection closed
+        let mut input = server.join().unwrap();
+        assert_eq!(input.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_truncated_message_is_a_transport_error() {
+        let (client, server) = MemoryTransport::duplex();
+        let (mut input, mut output) = server.split();
+        let exec = Executor::with_transport(client).expect("Failed to set up transport");
+
+        let server = thread::spawn(move || {
+            let _: (u8, serde::de::IgnoredAny) = rmp_serde::from_read(&mut input).expect("Failed to decode request");
+            // [0x21, {requestId: ...}], then the server goes away
+            output.write_all(&[0x92, 0x21, 0x81, 0xA9, b'r', b'e', b'q']).unwrap();
+        });
+
+        let err = exec.senrec(create_evaluator_request()).unwrap_err();
+        assert!(matches!(err, Error::Transport(ref e) if e.kind() == io::ErrorKind::UnexpectedEof), "{err}");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_explicit_command_wins() {
+        let explicit = vec!["java".to_string(), "-jar".into(), "pkl.jar".into(), "server".into()];
+        let cmd = resolve_pkl_command(Some(explicit.clone()), Some("/usr/bin/pkl".into()), None).unwrap();
+
+        assert_eq!(cmd, explicit);
+    }
+
+    #[test]
+    fn test_pkl_exec_is_split() {
+        let cmd = resolve_pkl_command(None, Some("java  -jar pkl.jar".into()), None).unwrap();
+
+        assert_eq!(cmd, vec!["java", "-jar", "pkl.jar", "server"]);
+    }
+
+    #[test]
+    fn test_path_lookup() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("pkl-bind-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pkl = dir.join("pkl");
+        std::fs::write(&pkl, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&pkl, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        let cmd = resolve_pkl_command(None, None, Some(path)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(cmd, vec![pkl.to_string_lossy().into_owned(), "server".into()]);
+    }
+
+    #[test]
+    fn test_pkl_not_found() {
+        let res = resolve_pkl_command(None, None, Some("/nonexistent".into()));
+
+        assert!(matches!(res, Err(Error::PklNotFound)));
+    }
 }
@@ -0,0 +1,461 @@
+//! A scriptable stand-in for `pkl server`
+//!
+//! The fake server speaks the message passing protocol from
+//! [`msg_api`](super::msg_api) over a [`MemoryTransport`], so the whole
+//! evaluator pipeline can be exercised without a pkl binary. Available in
+//! this crate's tests and to downstream crates through the `test-support`
+//! feature.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use pkl_bind::evaluator::fake_server::{Action, FakeServer};
+//! use pkl_bind::evaluator::{evaluator::EvaluatorMethods, evaluator_manager::EvaluatorManager, module_source::text_source};
+//!
+//! let (exec, server) = FakeServer::new()
+//!     .evaluator(42)
+//!     .evaluation([Action::respond_value("done")])
+//!     .start();
+//!
+//! let manager = EvaluatorManager::with_executor(exec);
+//! let evaluator = manager.new_evaluator(None).unwrap();
+//! assert_eq!(evaluator.evaluator_id, 42);
+//!
+//! let text: String = evaluator.evaluate_expression(&text_source("x = \"done\"".into()), "x").unwrap();
+//! # drop((evaluator, manager));
+//! # server.join();
+//! ```
+
+use std::{collections::{HashSet, VecDeque}, io::{Read, Write}, sync::{Arc, Mutex}, thread::{self, JoinHandle}};
+
+use rmp_serde::{config::BytesMode, Serializer};
+use serde::Serialize;
+
+#[cfg(feature = "async")]
+use super::async_executor::AsyncExecutor;
+use super::{executor::Executor, msg_api::{code::*, frame::{read_frame, Frame, MAX_MESSAGE_SIZE}, incoming::*, outgoing::*}, transport::{MemoryReader, MemoryTransport, MemoryWriter}};
+
+/// A step the server performs while answering an `Evaluate` request
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Finish the evaluation with this pkl binary encoded result
+    Respond(Vec<u8>),
+    /// Finish the evaluation with this error text
+    Fail(String),
+    /// Send a `Log` message for the evaluator
+    Log { level: i8, message: String, frame_uri: String },
+    /// Ask the client to read a module and wait for its answer
+    ReadModule(String),
+    /// Ask the client to read a resource and wait for its answer
+    ReadResource(String),
+    /// Ask the client to list modules and wait for its answer
+    ListModules(String),
+    /// Ask the client to list resources and wait for its answer
+    ListResources(String),
+}
+
+impl Action {
+    /// Finish the evaluation with `value` encoded as msgpack
+    pub fn respond_value(value: impl Serialize) -> Self {
+        Action::Respond(encode(&value))
+    }
+
+    /// Send a `Log` message, `level` 0 is trace and 1 is warn
+    pub fn log(level: i8, message: impl Into<String>, frame_uri: impl Into<String>) -> Self {
+        Action::Log { level, message: message.into(), frame_uri: frame_uri.into() }
+    }
+}
+
+/// Builder for a scripted fake pkl server
+///
+/// `CreateEvaluator` requests are answered from the [`FakeServer::evaluator`]
+/// and [`FakeServer::fail_create`] script, falling back to sequential ids.
+/// Each `Evaluate` request consumes the next [`FakeServer::evaluation`]
+/// script, and fails if there is none left. Evaluations are played one at a
+/// time, requests arriving while the server waits for a callback answer are
+/// handled once the current evaluation is done.
+#[derive(Debug, Default)]
+pub struct FakeServer {
+    creates: VecDeque<Result<i64, String>>,
+    evaluations: VecDeque<Vec<Action>>,
+}
+
+impl FakeServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Answer the next `CreateEvaluator` request with `evaluator_id`
+    pub fn evaluator(mut self, evaluator_id: i64) -> Self {
+        self.creates.push_back(Ok(evaluator_id));
+        self
+    }
+
+    /// Answer the next `CreateEvaluator` request with an error
+    pub fn fail_create(mut self, error: impl Into<String>) -> Self {
+        self.creates.push_back(Err(error.into()));
+        self
+    }
+
+    /// Script the answer to the next `Evaluate` request
+    pub fn evaluation(mut self, actions: impl IntoIterator<Item = Action>) -> Self {
+        self.evaluations.push_back(actions.into_iter().collect());
+        self
+    }
+
+    /// Start serving on one end of a [`MemoryTransport`]
+    ///
+    /// Returns the other end, the server stops once it is dropped.
+    pub fn serve(self) -> (MemoryTransport, FakeServerHandle) {
+        let (client, server) = MemoryTransport::duplex();
+        let (input, output) = server.split();
+
+        let received: Arc<Mutex<Vec<OutgoingMessage>>> = Default::default();
+        let session = Session {
+            script: self,
+            input,
+            output,
+            received: received.clone(),
+            backlog: Default::default(),
+            open: Default::default(),
+            next_id: 1,
+        };
+        let thread = thread::spawn(move || session.run());
+
+        (client, FakeServerHandle { received, thread })
+    }
+
+    /// Start serving and connect an [`Executor`] to it
+    pub fn start(self) -> (Executor, FakeServerHandle) {
+        let (client, handle) = self.serve();
+        let exec = Executor::with_transport(client).expect("memory transports always connect");
+
+        (exec, handle)
+    }
+
+    /// Start serving and connect an [`AsyncExecutor`] to it
+    ///
+    /// Must be called from within a tokio runtime. The server itself stays
+    /// blocking, a thread per direction copies bytes between it and the
+    /// executor's streams.
+    #[cfg(feature = "async")]
+    pub fn start_async(self) -> (AsyncExecutor, FakeServerHandle) {
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        let (client, handle) = self.serve();
+        let (mut from_server, mut to_server) = client.split();
+
+        // one stream per direction, so each side closes as soon as its writer is dropped
+        let (requests, mut server_in) = duplex(64 * 1024);
+        let (mut server_out, responses) = duplex(64 * 1024);
+        let runtime = tokio::runtime::Handle::current();
+
+        let rt = runtime.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            while let Ok(n @ 1..) = from_server.read(&mut buf) {
+                if rt.block_on(server_out.write_all(&buf[..n])).is_err() {
+                    break;
+                }
+            }
+        });
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            while let Ok(n @ 1..) = runtime.block_on(server_in.read(&mut buf)) {
+                if to_server.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (AsyncExecutor::with_streams(responses, requests), handle)
+    }
+}
+
+/// A running fake server
+#[derive(Debug)]
+pub struct FakeServerHandle {
+    received: Arc<Mutex<Vec<OutgoingMessage>>>,
+    thread: JoinHandle<()>,
+}
+
+impl FakeServerHandle {
+    /// Every message the server has received so far
+    pub fn received(&self) -> Vec<OutgoingMessage> {
+        self.received.lock().expect("received lock poisoned").clone()
+    }
+
+    /// Wait for the client to disconnect, returning every message received
+    pub fn join(self) -> Vec<OutgoingMessage> {
+        let FakeServerHandle { received, thread } = self;
+        thread.join().expect("fake server panicked");
+
+        let received = received.lock().expect("received lock poisoned");
+        received.clone()
+    }
+}
+
+struct Session {
+    script: FakeServer,
+    input: MemoryReader,
+    output: MemoryWriter,
+    received: Arc<Mutex<Vec<OutgoingMessage>>>,
+    /// Requests put aside while waiting for a callback answer
+    backlog: VecDeque<OutgoingMessage>,
+    open: HashSet<i64>,
+    next_id: i64,
+}
+
+impl Session {
+    fn run(mut self) {
+        while let Some(msg) = self.backlog.pop_front().or_else(|| self.recv()) {
+            match msg {
+                OutgoingMessage::CreateEvaluator(req) => self.create(req),
+                OutgoingMessage::Evaluate(req) => self.evaluate(req),
+                OutgoingMessage::CloseEvaluator(req) => {
+                    if let Some(id) = req.evaluator_id {
+                        self.open.remove(&id);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn recv(&mut self) -> Option<OutgoingMessage> {
+        let msg = read_request(&mut self.input)?;
+        self.received.lock().expect("received lock poisoned").push(msg.clone());
+        Some(msg)
+    }
+
+    fn send(&mut self, msg: IncomingMessage) {
+        // the client hanging up is noticed on the next read
+        let _ = self.output.write_all(&pack_response(&msg));
+    }
+
+    fn create(&mut self, req: CreateEvaluator) {
+        let scripted = self.script.creates.pop_front().unwrap_or_else(|| {
+            while self.open.contains(&self.next_id) {
+                self.next_id += 1;
+            }
+            Ok(self.next_id)
+        });
+
+        let (evaluator_id, error) = match scripted {
+            Ok(id) => {
+                self.open.insert(id);
+                (Some(id), None)
+            },
+            Err(error) => (None, Some(error)),
+        };
+
+        self.send(IncomingMessage::CreateEvaluatorResponse(CreateEvaluatorResponse {
+            request_id: req.request_id,
+            evaluator_id,
+            error,
+        }));
+    }
+
+    fn evaluate(&mut self, req: Evaluate) {
+        let respond = |result, error| IncomingMessage::EvaluateResponse(EvaluateResponse {
+            request_id: req.request_id,
+            evaluator_id: req.evaluator_id,
+            result,
+            error,
+        });
+
+        if !self.open.contains(&req.evaluator_id) {
+            let error = format!("Evaluator with ID {} was not found.", req.evaluator_id);
+            return self.send(respond(None, Some(error)));
+        }
+
+        let Some(actions) = self.script.evaluations.pop_front() else {
+            return self.send(respond(None, Some("fake server: no scripted evaluation left".into())));
+        };
+
+        for action in actions {
+            let evaluator_id = req.evaluator_id;
+            let request_id = self.next_request_id();
+
+            let callback = match action {
+                Action::Respond(result) => return self.send(respond(Some(result), None)),
+                Action::Fail(error) => return self.send(respond(None, Some(error))),
+                Action::Log { level, message, frame_uri } => {
+                    self.send(IncomingMessage::Log(Log { evaluator_id, level, message, frame_uri }));
+                    continue;
+                },
+                Action::ReadModule(uri) => IncomingMessage::ReadModule(ReadModule { request_id, evaluator_id, uri }),
+                Action::ReadResource(uri) => IncomingMessage::ReadResource(ReadResource { request_id, evaluator_id, uri }),
+                Action::ListModules(uri) => IncomingMessage::ListModules(ListModules { request_id, evaluator_id, uri }),
+                Action::ListResources(uri) => IncomingMessage::ListResources(ListResources { request_id, evaluator_id, uri }),
+            };
+
+            self.send(callback);
+            // wait for the client to answer the callback
+            while let Some(msg) = self.recv() {
+                if reply_request_id(&msg) == Some(request_id) {
+                    break;
+                }
+                self.backlog.push_back(msg);
+            }
+        }
+
+        // the script did not finish the evaluation
+        self.send(respond(None, Some("fake server: evaluation script ended without a result".into())));
+    }
+
+    fn next_request_id(&mut self) -> i64 {
+        self.next_id += 1;
+        -self.next_id
+    }
+}
+
+fn reply_request_id(msg: &OutgoingMessage) -> Option<i64> {
+    match msg {
+        OutgoingMessage::ReadResourceResponse(x) => Some(x.request_id),
+        OutgoingMessage::ReadModuleResponse(x) => Some(x.request_id),
+        OutgoingMessage::ListResourceResponse(x) => Some(x.request_id),
+        OutgoingMessage::ListModulesResponse(x) => Some(x.request_id),
+        _ => None,
+    }
+}
+
+/// Encode `value` as msgpack the way pkl does, byte arrays as `bin`
+fn encode(value: &impl Serialize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    value.serialize(&mut Serializer::new(&mut buf).with_struct_map().with_bytes(BytesMode::ForceAll))
+        .expect("Failed to encode value");
+    buf
+}
+
+fn pack_response(msg: &IncomingMessage) -> Vec<u8> {
+    match msg {
+        IncomingMessage::CreateEvaluatorResponse(x) => encode(&(CODE_NEW_EVALUATOR_RESPONSE, x)),
+        IncomingMessage::EvaluateResponse(x) => encode(&(CODE_EVALUATE_RESPONSE, x)),
+        IncomingMessage::ReadResource(x) => encode(&(CODE_EVALUATE_READ, x)),
+        IncomingMessage::ReadModule(x) => encode(&(CODE_EVALUATE_READ_MODULE, x)),
+        IncomingMessage::ListResources(x) => encode(&(CODE_LIST_RESOURCES_REQUEST, x)),
+        IncomingMessage::ListModules(x) => encode(&(CODE_LIST_MODULES_REQUEST, x)),
+        IncomingMessage::Log(x) => encode(&(CODE_EVALUATE_LOG, x)),
+    }
+}
+
+/// Decode a message sent by the client, `None` once it hangs up
+fn read_request(input: &mut impl Read) -> Option<OutgoingMessage> {
+    let frame = read_frame(input, MAX_MESSAGE_SIZE).ok()?;
+
+    let msg = match frame.code {
+        CODE_NEW_EVALUATOR => OutgoingMessage::CreateEvaluator(body(&frame)),
+        CODE_CLOSE_EVALUATOR => OutgoingMessage::CloseEvaluator(body(&frame)),
+        CODE_EVALUATE => OutgoingMessage::Evaluate(body(&frame)),
+        CODE_EVALUATE_READ_RESPONSE => OutgoingMessage::ReadResourceResponse(body(&frame)),
+        CODE_EVALUATE_READ_MODULE_RESPONSE => OutgoingMessage::ReadModuleResponse(body(&frame)),
+        CODE_LIST_RESOURCES_RESPONSE => OutgoingMessage::ListResourceResponse(body(&frame)),
+        CODE_LIST_MODULES_RESPONSE => OutgoingMessage::ListModulesResponse(body(&frame)),
+        code => panic!("fake server: client sent unexpected message code {code:#04X}"),
+    };
+
+    Some(msg)
+}
+
+fn body<T: for<'a> serde::Deserialize<'a>>(frame: &Frame) -> T {
+    frame.decode_body().expect("fake server: client sent a malformed message")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use url::Url;
+
+    use crate::evaluator::{evaluator_options::EvaluatorOptions, executor::Callbacks, reader::ModuleReaderImpl};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct CustomFs;
+
+    impl ModuleReaderImpl for CustomFs {
+        fn scheme(&self) -> &str { "customfs" }
+        fn is_local(&self) -> bool { true }
+        fn has_hierarchical_uris(&self) -> bool { false }
+        fn is_globbable(&self) -> bool { false }
+        fn read(&self, _uri: &Url) -> std::io::Result<String> { Ok("bar = 1".into()) }
+        fn list(&self, _uri: &Url) -> std::io::Result<Vec<PathElement>> { Ok(vec![]) }
+    }
+
+    #[test]
+    fn test_scripted_evaluation() {
+        let (exec, server) = FakeServer::new()
+            .evaluator(7)
+            .evaluation([Action::log(1, "careful", "repl:text"), Action::ReadModule("customfs:/foo.pkl".into()), Action::respond_value(3)])
+            .start();
+
+        let created = exec.senrec(OutgoingMessage::CreateEvaluator(CreateEvaluator {
+            request_id: 1,
+            client_resource_readers: None,
+            client_module_readers: None,
+            module_paths: None,
+            env: None,
+            properties: None,
+            output_format: None,
+            allowed_modules: None,
+            allowed_resources: None,
+            root_dir: None,
+            cache_dir: None,
+            project: None,
+            timeout_seconds: None,
+        })).unwrap();
+        assert_eq!(created.evaluator_id(), Some(7));
+
+        let pending: crate::evaluator::executor::PendingRequests = Default::default();
+        let (send, recv) = channel();
+        pending.lock().unwrap().insert(2, send);
+        let opts = EvaluatorOptions::default().with_module_reader(CustomFs).with_logger(crate::evaluator::logger::NoopLogger);
+        exec.register_evaluator(7, pending, Callbacks::new(&opts));
+
+        exec.send(OutgoingMessage::Evaluate(Evaluate {
+            request_id: 2,
+            evaluator_id: 7,
+            module_uri: "repl:text".into(),
+            module_text: Some("foo = 3".into()),
+            expr: Some("foo".into()),
+        })).unwrap();
+
+        // the log and the read are handled by the reader thread
+        let Ok(IncomingMessage::EvaluateResponse(resp)) = recv.recv().unwrap() else { panic!("expected a response") };
+        assert_eq!(resp.result, Some(vec![0x03]));
+
+        drop(exec);
+        let received = server.join();
+        assert!(matches!(&received[2], OutgoingMessage::ReadModuleResponse(x) if x.contents.as_deref() == Some("bar = 1")));
+    }
+
+    #[test]
+    fn test_fail_create() {
+        let (exec, server) = FakeServer::new().fail_create("boom").start();
+
+        let created = exec.senrec(OutgoingMessage::CreateEvaluator(CreateEvaluator {
+            request_id: 1,
+            client_resource_readers: None,
+            client_module_readers: None,
+            module_paths: None,
+            env: None,
+            properties: None,
+            output_format: None,
+            allowed_modules: None,
+            allowed_resources: None,
+            root_dir: None,
+            cache_dir: None,
+            project: None,
+            timeout_seconds: None,
+        })).unwrap();
+
+        let IncomingMessage::CreateEvaluatorResponse(resp) = created else { panic!("expected a create response") };
+        assert_eq!(resp.evaluator_id, None);
+        assert_eq!(resp.error.as_deref(), Some("boom"));
+
+        drop(exec);
+        server.join();
+    }
+}
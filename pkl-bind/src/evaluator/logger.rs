@@ -1,50 +1,112 @@
+//! Destinations for the messages pkl logs while evaluating
+//!
+//! pkl logs the output of `trace()` and its warnings, e.g. about deprecated
+//! members. They are handed to the [`Logger`] set with
+//! [`EvaluatorOptions::with_logger`](super::evaluator_options::EvaluatorOptions::with_logger),
+//! which is a [`StderrLogger`] by default.
+
 use std::io::{self, Write};
 
-#[derive(Default)]
-pub enum Logger {
-    #[default] StderrLogger,
-}
+use super::msg_api::incoming::Log;
 
-#[derive(Default)]
-struct StderrLogger {}
+/// Receives the messages pkl logs while evaluating
+///
+/// # Example
+///
+/// ```
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, logger::Logger};
+///
+/// struct Prefixed(&'static str);
+///
+/// impl Logger for Prefixed {
+///     fn trace(&self, message: &str, frame_uri: &str) {
+///         println!("{} trace: {message} ({frame_uri})", self.0);
+///     }
+///
+///     fn warn(&self, message: &str, frame_uri: &str) {
+///         println!("{} warning: {message} ({frame_uri})", self.0);
+///     }
+/// }
+///
+/// let opts = EvaluatorOptions::preconfigured().with_logger(Prefixed("config"));
+/// ```
+pub trait Logger: Send + Sync {
+    /// The output of a `trace()` call, `frame_uri` is the module it was called from
+    fn trace(&self, message: &str, frame_uri: &str);
 
-trait LoggerImpl {
-    fn trace(message: String, frame_uri: String) -> std::io::Result<()>;
-    fn warn(message: String, frame_uri: String) -> std::io::Result<()>;
+    /// A warning raised while evaluating the module at `frame_uri`
+    fn warn(&self, message: &str, frame_uri: &str);
 }
 
-impl Logger {
-    pub fn trace(&self, message: String, frame_uri: String) {
-        match self {
-            Logger::StderrLogger => StderrLogger::trace(message, frame_uri).expect("Encountered an IO error in StderrLogger::trace()"),
+impl dyn Logger {
+    /// Deliver a `Log` message from the server, level 0 is a trace and anything above a warning
+    pub(crate) fn log(&self, msg: &Log) {
+        match msg.level {
+            0 => self.trace(&msg.message, &msg.frame_uri),
+            _ => self.warn(&msg.message, &msg.frame_uri),
         }
     }
+}
 
-    pub fn warn(&self, message: String, frame_uri: String) {
-        match self {
-            Logger::StderrLogger => StderrLogger::warn(message, frame_uri).expect("Encountered an IO error in StderrLogger::warn()"),
-        }
+/// Writes every message to stderr on a line of its own, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrLogger;
+
+impl StderrLogger {
+    fn write(level: &str, message: &str, frame_uri: &str) {
+        // there is nowhere left to report a failed write to stderr
+        let _ = writeln!(io::stderr().lock(), "{level}: {message} ({frame_uri})");
     }
 }
 
-impl LoggerImpl for StderrLogger {
-    fn trace(message: String, frame_uri: String) -> std::io::Result<()> {
-        let stderr = io::stderr();
-        let mut handle = stderr.lock();
+impl Logger for StderrLogger {
+    fn trace(&self, message: &str, frame_uri: &str) {
+        Self::write("TRACE", message, frame_uri);
+    }
+
+    fn warn(&self, message: &str, frame_uri: &str) {
+        Self::write("WARN", message, frame_uri);
+    }
+}
 
-        let s: String = format!("TRACE: {} {}", message, frame_uri);
+/// Discards every message
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopLogger;
 
-        write!(handle, "{}", s)?;
-        Ok(())
+impl Logger for NoopLogger {
+    fn trace(&self, _message: &str, _frame_uri: &str) {}
+
+    fn warn(&self, _message: &str, _frame_uri: &str) {}
+}
+
+/// Forwards messages to the [`log`] facade under the target `pkl`
+#[cfg(feature = "log")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogLogger;
+
+#[cfg(feature = "log")]
+impl Logger for LogLogger {
+    fn trace(&self, message: &str, frame_uri: &str) {
+        log::trace!(target: "pkl", "{message} ({frame_uri})");
     }
 
-    fn warn(message: String, frame_uri: String) -> std::io::Result<()> {
-        let stderr = io::stderr();
-        let mut handle = stderr.lock();
+    fn warn(&self, message: &str, frame_uri: &str) {
+        log::warn!(target: "pkl", "{message} ({frame_uri})");
+    }
+}
 
-        let s: String = format!("WARN: {} {}", message, frame_uri);
+/// Emits messages as [`tracing`] events under the target `pkl`, with `frame_uri` as a field
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingLogger;
+
+#[cfg(feature = "tracing")]
+impl Logger for TracingLogger {
+    fn trace(&self, message: &str, frame_uri: &str) {
+        tracing::trace!(target: "pkl", frame_uri, "{message}");
+    }
 
-        write!(handle, "{}", s)?;
-        Ok(())
+    fn warn(&self, message: &str, frame_uri: &str) {
+        tracing::warn!(target: "pkl", frame_uri, "{message}");
     }
 }
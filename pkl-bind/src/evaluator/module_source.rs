@@ -17,10 +17,10 @@ pub struct ModuleSource {
 
 impl ModuleSource {
     /// Immutable access for the uri of a ModuleSource
-    pub fn uri(&self) -> &Url {return &self.uri;}
+    pub fn uri(&self) -> &Url {&self.uri}
 
     /// Immutable access for the contents of a ModuleSource
-    pub fn contents(&self) -> &Option<String> {return &self.contents}
+    pub fn contents(&self) -> &Option<String> {&self.contents}
 }
 
 /// Builds a ModuleSource from a file path
@@ -35,22 +35,20 @@ impl ModuleSource {
 /// // assert_eq!(source.uri.path(), "/home/$USER/$DIR/test/file.pkl");
 /// ```
 pub fn file_source(path: PathBuf) -> ModuleSource {
-    let result: PathBuf;
     //TODO fix, this is sloppy
     let url_entry = Url::parse_with_params("file:/", &[("scheme", "file")]).expect("Failed to convert path to uri");
 
-    if !path.is_absolute() {
+    let result: PathBuf = if !path.is_absolute() {
         let pwd: PathBuf = current_dir().expect("Failed to resolve current working dir");
-        result = pwd.join(&path);
-        println!("Absolute path: {:?}", result);
+        pwd.join(&path)
     } else {
-        result = path;
-    }
+        path
+    };
 
     let url_string: &str = result.to_str().expect("Filed to convert path to string");
     let res = url_entry.join(url_string).expect("Failed to join to the rest of the path");
 
-    return ModuleSource{uri: res, contents: None};
+    ModuleSource{uri: res, contents: None}
 }
 
 /// Builds a ModuleSource from a string input
@@ -66,7 +64,7 @@ pub fn file_source(path: PathBuf) -> ModuleSource {
 pub fn text_source(text: String) -> ModuleSource {
     let uri_entry = Url::parse("repl:/").expect("Failed to parse uri entry");
     //TODO this is also sloppy
-    return ModuleSource{ uri: uri_entry, contents: Some(text), };
+    ModuleSource{ uri: uri_entry, contents: Some(text), }
 }
 
 /// Builds a ModuleSource using the input uri
@@ -80,5 +78,5 @@ pub fn text_source(text: String) -> ModuleSource {
 /// assert_eq!(pkl.uri().scheme(), "file");
 /// ```
 pub fn uri_source(uri: Url) -> ModuleSource {
-    return ModuleSource{ uri, contents: None };
+    ModuleSource{ uri, contents: None }
 }
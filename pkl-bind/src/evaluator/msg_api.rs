@@ -3,5 +3,6 @@ extern crate serde;
 extern crate rmp;
 
 pub mod code;
+pub mod frame;
 pub mod incoming;
 pub mod outgoing;
@@ -1,6 +1,6 @@
-/// Message passing API codes for communication between server and client pkl
-/// see https://pkl-lang.org/main/current/bindings-specification/index.html for
-/// more details
+//! Message passing API codes for communication between server and client pkl
+//! see https://pkl-lang.org/main/current/bindings-specification/index.html for
+//! more details
 
 //TODO this should be an enum
 pub const CODE_NEW_EVALUATOR                 :u8 = 0x20;
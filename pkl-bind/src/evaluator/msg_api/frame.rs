@@ -0,0 +1,300 @@
+//! Framing of the `[code, body]` messages exchanged with pkl
+//!
+//! Every message is a single msgpack value, so a frame is found by walking
+//! the value marker by marker until it is complete. The size is checked
+//! against the limit as every header comes in, so an oversized message is
+//! rejected before its contents are read. Malformed or unknown messages are
+//! consumed whole, which keeps the stream in sync for the next message, but
+//! after an oversized one or anything that is not msgpack the stream can't
+//! be trusted and is given up.
+
+use std::{fmt, io::{self, Read}};
+
+use rmp::Marker;
+
+/// Largest message accepted from the other side, in bytes
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A single `[code, body]` message, the body is left encoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub code: u8,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Decode the body as `T`
+    pub fn decode_body<T: for<'a> serde::Deserialize<'a>>(&self) -> Result<T, FrameError> {
+        rmp_serde::from_slice(&self.body).map_err(|source| FrameError::Body { code: self.code, source })
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The stream ended cleanly, before the first byte of a frame
+    Closed,
+    /// Reading from the stream failed, `UnexpectedEof` if it ended mid-frame
+    Io(io::Error),
+    /// The value read is not a `[code, body]` pair
+    Envelope(&'static str),
+    /// The message exceeds the size limit, `size` is a lower bound
+    TooLarge { size: u64, limit: usize },
+    /// The stream is not msgpack, so the end of the message can't be found
+    Corrupt(&'static str),
+    /// The message code is not one we expect
+    UnknownCode(u8),
+    /// The body does not match the message code
+    Body { code: u8, source: rmp_serde::decode::Error },
+}
+
+impl FrameError {
+    /// Whether the stream can't produce any further messages
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, FrameError::Closed | FrameError::Io(_) | FrameError::TooLarge { .. } | FrameError::Corrupt(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "the stream was closed"),
+            FrameError::Io(err) => write!(f, "failed to read message: {err}"),
+            FrameError::Envelope(reason) => write!(f, "malformed message: {reason}"),
+            FrameError::TooLarge { size, limit } => write!(f, "message of at least {size} bytes exceeds the limit of {limit} bytes"),
+            FrameError::Corrupt(reason) => write!(f, "corrupt message stream: {reason}"),
+            FrameError::UnknownCode(code) => write!(f, "unknown message code {code:#04X}"),
+            FrameError::Body { code, source } => write!(f, "failed to decode body of message {code:#04X}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(err) => Some(err),
+            FrameError::Body { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// Read one frame from `input`, rejecting messages larger than `limit` bytes
+pub fn read_frame(input: &mut impl Read, limit: usize) -> Result<Frame, FrameError> {
+    let raw = read_value(input, limit)?;
+    parse_envelope(&raw)
+}
+
+/// Split a complete msgpack value into its code and body
+pub(crate) fn parse_envelope(raw: &[u8]) -> Result<Frame, FrameError> {
+    let mut rd = raw;
+
+    let len = rmp::decode::read_array_len(&mut rd).map_err(|_| FrameError::Envelope("expected an array"))?;
+    if len != 2 {
+        return Err(FrameError::Envelope("expected an array of two elements"));
+    }
+    let code: u8 = rmp::decode::read_int(&mut rd).map_err(|_| FrameError::Envelope("expected an integer message code"))?;
+
+    match rd.first().map(|&b| Marker::from_u8(b)) {
+        Some(Marker::FixMap(_) | Marker::Map16 | Marker::Map32) => (),
+        _ => return Err(FrameError::Envelope("expected a map as message body")),
+    }
+
+    Ok(Frame { code, body: rd.to_vec() })
+}
+
+/// Progress of a [`FrameScanner`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scan {
+    /// The first `len` bytes hold a whole value
+    Complete(usize),
+    /// At least this many more bytes are needed
+    Needs(usize),
+}
+
+/// Finds the end of one msgpack value in a buffer that grows as bytes arrive
+///
+/// Headers are parsed as soon as they are buffered, and scanning resumes
+/// where it stopped when called again with more bytes, so a value is never
+/// walked twice.
+#[derive(Debug)]
+pub(crate) struct FrameScanner {
+    /// Offset of the next marker, may lie past the buffer while skipping data
+    pos: u64,
+    /// Number of values still to be read, containers add their elements
+    remaining: u64,
+    limit: usize,
+}
+
+impl FrameScanner {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self { pos: 0, remaining: 1, limit }
+    }
+
+    /// Continue scanning `buf`, which starts with the bytes scanned so far
+    pub(crate) fn scan(&mut self, buf: &[u8]) -> Result<Scan, FrameError> {
+        let buffered = buf.len() as u64;
+        loop {
+            if self.pos > buffered {
+                return Ok(Scan::Needs((self.pos - buffered) as usize));
+            }
+            if self.remaining == 0 {
+                return Ok(Scan::Complete(self.pos as usize));
+            }
+            let Some(&byte) = buf.get(self.pos as usize) else {
+                return Ok(Scan::Needs(1));
+            };
+
+            let marker = Marker::from_u8(byte);
+            let header = match marker {
+                Marker::Str8 | Marker::Bin8 | Marker::Ext8 => 1,
+                Marker::Str16 | Marker::Bin16 | Marker::Ext16 | Marker::Array16 | Marker::Map16 => 2,
+                Marker::Str32 | Marker::Bin32 | Marker::Ext32 | Marker::Array32 | Marker::Map32 => 4,
+                _ => 0,
+            };
+
+            let start = self.pos as usize + 1;
+            let Some(len_bytes) = buf.get(start..start + header) else {
+                return Ok(Scan::Needs(start + header - buf.len()));
+            };
+            let len = len_bytes.iter().fold(0, |len, &b| (len << 8) | b as u64);
+
+            // (bytes following the header, values added)
+            let (data, values) = match marker {
+                Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => (0, 0),
+                Marker::U8 | Marker::I8 => (1, 0),
+                Marker::U16 | Marker::I16 => (2, 0),
+                Marker::U32 | Marker::I32 | Marker::F32 => (4, 0),
+                Marker::U64 | Marker::I64 | Marker::F64 => (8, 0),
+                Marker::FixStr(n) => (n as u64, 0),
+                Marker::Str8 | Marker::Str16 | Marker::Str32 | Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => (len, 0),
+                Marker::FixArray(n) => (0, n as u64),
+                Marker::Array16 | Marker::Array32 => (0, len),
+                Marker::FixMap(n) => (0, 2 * n as u64),
+                Marker::Map16 | Marker::Map32 => (0, 2 * len),
+                // extension type byte followed by the data
+                Marker::FixExt1 => (2, 0),
+                Marker::FixExt2 => (3, 0),
+                Marker::FixExt4 => (5, 0),
+                Marker::FixExt8 => (9, 0),
+                Marker::FixExt16 => (17, 0),
+                Marker::Ext8 | Marker::Ext16 | Marker::Ext32 => (len + 1, 0),
+                Marker::Reserved => return Err(FrameError::Corrupt("reserved msgpack marker")),
+            };
+
+            self.pos += 1 + header as u64 + data;
+            self.remaining = self.remaining - 1 + values;
+
+            // every value still to be read takes at least one byte
+            let size = self.pos + self.remaining;
+            if size > self.limit as u64 {
+                return Err(FrameError::TooLarge { size, limit: self.limit });
+            }
+        }
+    }
+}
+
+/// Read exactly one msgpack value from `input` and return its raw bytes
+///
+/// Only the bytes of the value are read, nothing past its end. A stream that
+/// ends before the value starts is [`FrameError::Closed`].
+fn read_value(input: &mut impl Read, limit: usize) -> Result<Vec<u8>, FrameError> {
+    let mut scanner = FrameScanner::new(limit);
+    let mut buf = Vec::new();
+
+    loop {
+        match scanner.scan(&buf)? {
+            Scan::Complete(_) => return Ok(buf),
+            Scan::Needs(n) => {
+                let start = buf.len();
+                buf.resize(start + n, 0);
+                match input.read_exact(&mut buf[start..]) {
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && start == 0 => return Err(FrameError::Closed),
+                    res => res?,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &impl serde::Serialize) -> Vec<u8> {
+        rmp_serde::to_vec(value).unwrap()
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let mut body = std::collections::HashMap::new();
+        body.insert("message", vec![1u8, 2, 3]);
+        let mut data = encode(&(0x25, &body));
+        data.extend(encode(&(0x21, &body)));
+
+        let mut input = &data[..];
+        let first = read_frame(&mut input, MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(first.code, 0x25);
+        assert_eq!(first.body, encode(&body));
+
+        let second = read_frame(&mut input, MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(second.code, 0x21);
+        assert!(matches!(read_frame(&mut input, MAX_MESSAGE_SIZE), Err(FrameError::Closed)));
+    }
+
+    #[test]
+    fn test_bad_frames_keep_sync() {
+        let mut body = std::collections::HashMap::new();
+        body.insert("text", "x".repeat(100));
+
+        let mut data = encode(&(0x25, 1, 2));
+        data.extend(encode(&(0x25, "not a map")));
+        data.extend(encode(&(0x21, &body)));
+
+        let mut input = &data[..];
+        assert!(matches!(read_frame(&mut input, 64), Err(FrameError::Envelope(_))));
+        assert!(matches!(read_frame(&mut input, 64), Err(FrameError::Envelope(_))));
+        assert!(matches!(read_frame(&mut input, 64), Err(FrameError::TooLarge { limit: 64, .. })));
+    }
+
+    #[test]
+    fn test_oversized_header_is_rejected_early() {
+        // [0x25, {text: <str32 of 1 GiB>}], only the headers are ever sent
+        let mut data = vec![0x92, 0x25, 0x81, 0xA4, b't', b'e', b'x', b't', 0xDB, 0x40, 0x00, 0x00, 0x00];
+        data.extend_from_slice(b"never read");
+
+        let mut input = &data[..];
+        let err = read_frame(&mut input, MAX_MESSAGE_SIZE).unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge { size, .. } if size > 1 << 30));
+        assert!(err.is_fatal());
+        assert_eq!(input, b"never read");
+
+        // an array announcing more elements than could fit
+        let data = [0x92, 0x25, 0xDD, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(matches!(read_frame(&mut &data[..], 1024), Err(FrameError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn test_scanner_resumes() {
+        let data = encode(&(0x25, std::collections::HashMap::from([("message", "hello")])));
+
+        let mut scanner = FrameScanner::new(MAX_MESSAGE_SIZE);
+        for end in 0..data.len() {
+            assert!(matches!(scanner.scan(&data[..end]).unwrap(), Scan::Needs(n) if n > 0));
+        }
+        assert_eq!(scanner.scan(&data).unwrap(), Scan::Complete(data.len()));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let data = encode(&(0x25, std::collections::HashMap::from([("message", "hello")])));
+
+        let err = read_frame(&mut &data[..data.len() - 2], MAX_MESSAGE_SIZE).unwrap_err();
+        assert!(matches!(err, FrameError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -1,10 +1,91 @@
+use std::io::Read;
+
 use rmp_serde as rmps;
 
 use rmps::from_slice;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+use super::{code::MessageCode, frame::{read_frame, Frame, FrameError, MAX_MESSAGE_SIZE}};
+
+pub fn decode<T: for<'a> Deserialize<'a>>(msg: Vec<u8>) -> Result<(u8, T), Error> {
+    from_slice::<(u8, T)>(&msg).map_err(|err| Error::Protocol(err.to_string()))
+}
+
+/// Outcome of decoding one message from the server
+#[derive(Debug)]
+pub enum Decoded {
+    Message(IncomingMessage),
+    /// The message could not be decoded and was dropped, the stream is still in sync
+    Skipped { error: FrameError, ids: MessageIds },
+    /// The stream can't be trusted any more, e.g. after an oversized message or a failed read
+    Failed(FrameError),
+    /// The input ended cleanly between two messages
+    Incomplete,
+}
+
+/// The ids of a message that could not be decoded, as far as they can be made out
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageIds {
+    pub request_id: Option<i64>,
+    pub evaluator_id: Option<i64>,
+}
+
+impl MessageIds {
+    fn peek(frame: &Frame) -> Self {
+        frame.decode_body().unwrap_or_default()
+    }
+}
+
+/// Decode one `[code, body]` message from `out`
+pub fn decode_message(out: &mut impl Read) -> Decoded {
+    decode_message_with_limit(out, MAX_MESSAGE_SIZE)
+}
+
+/// Decode one message from `out`, dropping it if it is larger than `limit` bytes
+pub fn decode_message_with_limit(out: &mut impl Read, limit: usize) -> Decoded {
+    match read_frame(out, limit) {
+        Ok(frame) => decode_or_skip(&frame),
+        Err(FrameError::Closed) => Decoded::Incomplete,
+        Err(error) if error.is_fatal() => Decoded::Failed(error),
+        Err(error) => Decoded::Skipped { error, ids: MessageIds::default() },
+    }
+}
 
-pub fn decode<T: for<'a> Deserialize<'a>>(msg: Vec<u8>) -> Result<(u8, T), &'static str> {
-    return Ok(from_slice::<(u8, T)>(&msg).expect("Failed to deserialize"));
+/// Decode a whole message that was already read into memory
+#[cfg(feature = "async")]
+pub(crate) fn decode_buffered(raw: &[u8]) -> Decoded {
+    match super::frame::parse_envelope(raw) {
+        Ok(frame) => decode_or_skip(&frame),
+        Err(error) => Decoded::Skipped { error, ids: MessageIds::default() },
+    }
+}
+
+fn decode_or_skip(frame: &Frame) -> Decoded {
+    match decode_frame(frame) {
+        Ok(msg) => Decoded::Message(msg),
+        Err(error) => Decoded::Skipped { error, ids: MessageIds::peek(frame) },
+    }
+}
+
+/// Decode the body of a frame sent by the server
+pub fn decode_frame(frame: &Frame) -> Result<IncomingMessage, FrameError> {
+    let code = MessageCode::try_from(frame.code).map_err(|_| FrameError::UnknownCode(frame.code))?;
+
+    // TODO not very DRY, but this might be the most idiomatic way to use serde
+    match code {
+        MessageCode::NewEvaluatorResponse => frame.decode_body().map(IncomingMessage::CreateEvaluatorResponse),
+        MessageCode::EvaluateResponse => frame.decode_body().map(IncomingMessage::EvaluateResponse),
+        MessageCode::EvaluateRead => frame.decode_body().map(IncomingMessage::ReadResource),
+        MessageCode::EvaluateReadModule => frame.decode_body().map(IncomingMessage::ReadModule),
+        MessageCode::ListResourcesRequest => frame.decode_body().map(IncomingMessage::ListResources),
+        MessageCode::ListModulesRequest => frame.decode_body().map(IncomingMessage::ListModules),
+        MessageCode::EvaluateLog => frame.decode_body().map(IncomingMessage::Log),
+        // requests only ever flow from the client to the server
+        _ => Err(FrameError::UnknownCode(frame.code)),
+    }
 }
 
 pub trait DeserializableMessage {
@@ -13,7 +94,7 @@ pub trait DeserializableMessage {
         Self: Sized;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum IncomingMessage {
     CreateEvaluatorResponse(CreateEvaluatorResponse),
     EvaluateResponse(EvaluateResponse),
@@ -24,6 +105,36 @@ pub enum IncomingMessage {
     Log(Log),
 }
 
+impl IncomingMessage {
+    /// The id of the request this message answers or belongs to, `Log` has none
+    pub fn request_id(&self) -> Option<i64> {
+        match self {
+            IncomingMessage::CreateEvaluatorResponse(msg) => Some(msg.request_id),
+            IncomingMessage::EvaluateResponse(msg) => Some(msg.request_id),
+            IncomingMessage::ReadResource(msg) => Some(msg.request_id),
+            IncomingMessage::ReadModule(msg) => Some(msg.request_id),
+            IncomingMessage::ListResources(msg) => Some(msg.request_id),
+            IncomingMessage::ListModules(msg) => Some(msg.request_id),
+            IncomingMessage::Log(_) => None,
+        }
+    }
+
+    /// The id of the evaluator this message is addressed to
+    pub fn evaluator_id(&self) -> Option<i64> {
+        match self {
+            IncomingMessage::CreateEvaluatorResponse(msg) => msg.evaluator_id,
+            IncomingMessage::EvaluateResponse(msg) => Some(msg.evaluator_id),
+            IncomingMessage::ReadResource(msg) => Some(msg.evaluator_id),
+            IncomingMessage::ReadModule(msg) => Some(msg.evaluator_id),
+            IncomingMessage::ListResources(msg) => Some(msg.evaluator_id),
+            IncomingMessage::ListModules(msg) => Some(msg.evaluator_id),
+            IncomingMessage::Log(msg) => Some(msg.evaluator_id),
+        }
+    }
+}
+
+// not used yet, kept private until they are
+#[allow(dead_code)]
 impl IncomingMessage {
     fn create_evaluator_response(&self) -> Option<CreateEvaluatorResponse> {
         if let IncomingMessage::CreateEvaluatorResponse(msg) = self {
@@ -76,7 +187,7 @@ impl IncomingMessage {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateEvaluatorResponse {
     pub request_id: i64,
@@ -87,14 +198,11 @@ impl DeserializableMessage for CreateEvaluatorResponse {
     fn deserialize(reader: &mut dyn std::io::Read) -> Option<Self>
     where
         Self: Sized {
-        match rmp_serde::from_read(reader) {
-            Ok(msg) => Some(msg),
-            Err(_) => None,
-        }
+        rmp_serde::from_read(reader).ok()
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct EvaluateResponse {
     pub request_id: i64,
@@ -104,7 +212,7 @@ pub struct EvaluateResponse {
 }
 
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ReadResource {
     pub request_id: i64,
@@ -112,7 +220,7 @@ pub struct ReadResource {
     pub uri: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ReadModule {
     pub request_id: i64,
@@ -120,7 +228,7 @@ pub struct ReadModule {
     pub uri: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ListResources {
     pub request_id: i64,
@@ -128,7 +236,7 @@ pub struct ListResources {
     pub uri: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ListModules {
     pub request_id: i64,
@@ -136,7 +244,7 @@ pub struct ListModules {
     pub uri: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Log {
     pub evaluator_id: i64,
@@ -159,7 +267,7 @@ mod tests {
                                  0xD2, 0xFF, 0xFD, 0xED, 0x23, 0xA5, 0x65, 0x72, 0x72,
                                  0x6F, 0x72, 0xC0];
         let res: (u8, CreateEvaluatorResponse) = rmps::from_slice(&eval_response).unwrap();
-        let code = res.0.clone();
+        let code = res.0;
         let elem = res.1.clone();
 
         println!("Result of Deserialization: {:?}", res);
@@ -175,7 +283,7 @@ mod tests {
 
         // [ 33, {"requestId": 135, "evaluatorId": -135901 }] //note the ommissiono of the null field
         let res: (u8, CreateEvaluatorResponse) = rmps::from_slice(&eval_null).unwrap();
-        let code2 = res.0.clone();
+        let code2 = res.0;
         let elem2 = res.1.clone();
 
         println!("Result of Deserialization: {:?}", res);
@@ -184,4 +292,72 @@ mod tests {
         assert_eq!(elem2.evaluator_id, Some(-135901));
         assert_eq!(elem2.error, None);
     }
+
+    #[test]
+    fn test_read_message() {
+        // [0x25, {evaluatorId: 1, level: 1, message: "m", frameUri: "u"}]
+        let data = vec![0x92, 0x25, 0x84,
+                     0xAB, b'e', b'v', b'a', b'l', b'u', b'a', b't', b'o', b'r', b'I', b'd', 0x01,
+                     0xA5, b'l', b'e', b'v', b'e', b'l', 0x01,
+                     0xA7, b'm', b'e', b's', b's', b'a', b'g', b'e', 0xA1, b'm',
+                     0xA8, b'f', b'r', b'a', b'm', b'e', b'U', b'r', b'i', 0xA1, b'u'];
+
+        let mut reader = std::io::Cursor::new(data);
+        let Decoded::Message(msg) = decode_message(&mut reader) else { panic!("Failed to read message") };
+
+        assert!(matches!(msg, IncomingMessage::Log(Log { level: 1, .. })));
+        assert!(matches!(decode_message(&mut reader), Decoded::Incomplete));
+    }
+
+    #[test]
+    fn test_read_error_fails() {
+        struct Broken;
+
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        let failed = decode_message(&mut Broken);
+        assert!(matches!(failed, Decoded::Failed(FrameError::Io(ref e)) if e.kind() == std::io::ErrorKind::ConnectionReset));
+
+        // [0x25, {evaluatorId: 1, ...}], cut short
+        let mut reader = std::io::Cursor::new(vec![0x92, 0x25, 0x84, 0xAB, b'e', b'v']);
+        assert!(matches!(decode_message(&mut reader), Decoded::Failed(FrameError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_unknown_code_is_skipped() {
+        // [0x7f, {foo: [1, 2]}] followed by [0x25, {evaluatorId: 1, level: 0, message: "m", frameUri: "u"}]
+        let data = vec![0x92, 0x7F, 0x81, 0xA3, b'f', b'o', b'o', 0x92, 0x01, 0x02,
+                        0x92, 0x25, 0x84,
+                        0xAB, b'e', b'v', b'a', b'l', b'u', b'a', b't', b'o', b'r', b'I', b'd', 0x01,
+                        0xA5, b'l', b'e', b'v', b'e', b'l', 0x00,
+                        0xA7, b'm', b'e', b's', b's', b'a', b'g', b'e', 0xA1, b'm',
+                        0xA8, b'f', b'r', b'a', b'm', b'e', b'U', b'r', b'i', 0xA1, b'u'];
+
+        let mut reader = std::io::Cursor::new(data);
+        assert!(matches!(decode_message(&mut reader), Decoded::Skipped { error: FrameError::UnknownCode(0x7F), .. }));
+        assert!(matches!(decode_message(&mut reader), Decoded::Message(IncomingMessage::Log(Log { level: 0, .. }))));
+        assert!(matches!(decode_message(&mut reader), Decoded::Incomplete));
+    }
+
+    #[test]
+    fn test_skipped_message_ids() {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Malformed {
+            request_id: i64,
+            evaluator_id: i64,
+            error: i64,
+        }
+
+        // an EvaluateResponse whose error is not a string
+        let data = rmps::to_vec_named(&(0x24, Malformed { request_id: 5, evaluator_id: 1, error: 3 })).unwrap();
+
+        let Decoded::Skipped { error, ids } = decode_message(&mut &data[..]) else { panic!("expected a skipped message") };
+        assert!(matches!(error, FrameError::Body { code: 0x24, .. }));
+        assert_eq!(ids, MessageIds { request_id: Some(5), evaluator_id: Some(1) });
+    }
 }
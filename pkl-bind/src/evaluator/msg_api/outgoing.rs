@@ -1,22 +1,32 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write};
 
 use rmp_serde as rmps;
 
-use serde::Serialize;
-use rmps::Serializer;
+use serde::{Deserialize, Serialize};
+use rmps::{config::BytesMode, Serializer};
 
-use super::code::{CODE_NEW_EVALUATOR, CODE_NEW_EVALUATOR_RESPONSE, CODE_CLOSE_EVALUATOR, CODE_EVALUATE, CODE_EVALUATE_RESPONSE, CODE_EVALUATE_READ_RESPONSE, CODE_EVALUATE_READ_MODULE_RESPONSE, CODE_LIST_RESOURCES_RESPONSE, CODE_LIST_MODULES_REQUEST, CODE_LIST_MODULES_RESPONSE};
+use crate::Error;
+
+use super::code::{CODE_NEW_EVALUATOR, CODE_NEW_EVALUATOR_RESPONSE, CODE_CLOSE_EVALUATOR, CODE_EVALUATE, CODE_EVALUATE_RESPONSE, CODE_EVALUATE_READ_RESPONSE, CODE_EVALUATE_READ_MODULE_RESPONSE, CODE_LIST_RESOURCES_RESPONSE, CODE_LIST_MODULES_RESPONSE};
 
 /// Packs a message in messagepasing v5 format
 ///
 /// # Example
-pub fn pack_message(msg: OutgoingMessage) -> Result<Vec<u8>, &'static str> {
+pub fn pack_message(msg: OutgoingMessage) -> Result<Vec<u8>, Error> {
     let mut buf = Vec::new();
     let code = get_code(&msg).0;
     let value = (code, &msg);
 
-    let _ = &value.serialize(&mut Serializer::new(&mut buf).with_struct_map().with_binary()).unwrap();
-    return Ok(buf);
+    // byte arrays such as resource contents must be sent as msgpack `bin`
+    value.serialize(&mut Serializer::new(&mut buf).with_struct_map().with_bytes(BytesMode::ForceAll))?;
+    Ok(buf)
+}
+
+/// Packs a message and writes it to `out`
+pub fn write_message(out: &mut impl Write, msg: OutgoingMessage) -> Result<(), Error> {
+    let message = pack_message(msg)?;
+
+    out.write_all(&message).and_then(|()| out.flush()).map_err(Error::Transport)
 }
 
 fn get_code(t: &OutgoingMessage) -> (u8, Option<u8>) {
@@ -31,6 +41,8 @@ fn get_code(t: &OutgoingMessage) -> (u8, Option<u8>) {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
 pub enum OutgoingMessage {
     CreateEvaluator(CreateEvaluator),
     CloseEvaluator(CloseEvaluator),
@@ -41,6 +53,17 @@ pub enum OutgoingMessage {
     ListModulesResponse(ListModulesResponse),
 }
 
+impl OutgoingMessage {
+    /// The request id of messages that expect a reply from the server
+    pub fn request_id(&self) -> Option<i64> {
+        match self {
+            OutgoingMessage::CreateEvaluator(msg) => Some(msg.request_id),
+            OutgoingMessage::Evaluate(msg) => Some(msg.request_id),
+            _ => None,
+        }
+    }
+}
+
 impl Serialize for OutgoingMessage {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -57,7 +80,7 @@ impl Serialize for OutgoingMessage {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ModuleReader {
     pub scheme: String,
@@ -66,7 +89,7 @@ pub struct ModuleReader {
     pub is_local: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ResourceReader {
     pub scheme: String,
@@ -74,13 +97,13 @@ pub struct ResourceReader {
     pub is_globbable: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Checksums {
-    pub checksums: String,
+    pub sha256: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProjectOrDependency {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -90,10 +113,11 @@ pub struct ProjectOrDependency {
     pub project_file_uri: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub checksums: Option<Checksums>,
+    #[serde(default)]
     pub dependencies: HashMap<String, ProjectOrDependency>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateEvaluator {
     pub request_id: i64,
@@ -123,14 +147,14 @@ pub struct CreateEvaluator {
     pub timeout_seconds: Option<i64>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CloseEvaluator {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub evaluator_id: Option<i64>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Evaluate {
     pub request_id: i64,
@@ -142,7 +166,7 @@ pub struct Evaluate {
     pub expr: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ReadResourceResponse {
     pub request_id: i64,
@@ -153,7 +177,7 @@ pub struct ReadResourceResponse {
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ReadModuleResponse {
     pub request_id: i64,
@@ -164,7 +188,7 @@ pub struct ReadModuleResponse {
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListResourceResponse {
     pub request_id: i64,
@@ -174,7 +198,7 @@ pub struct ListResourceResponse {
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListModulesResponse {
     pub request_id: i64,
@@ -185,7 +209,7 @@ pub struct ListModulesResponse {
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PathElement {
     pub name: String,
@@ -223,4 +247,13 @@ mod tests {
 
         assert_eq!(mp, expected);
     }
+
+    #[test]
+    fn test_pack_resource_contents_as_bin() {
+        let resp = ReadResourceResponse { request_id: 1, evaluator_id: 2, contents: Some(vec![0xFF, 0x00]), error: None };
+
+        let mp = pack_message(OutgoingMessage::ReadResourceResponse(resp)).unwrap();
+        let contents = [0xA8, b'c', b'o', b'n', b't', b'e', b'n', b't', b's', 0xC4, 0x02, 0xFF, 0x00];
+        assert!(mp.ends_with(&contents), "{mp:X?}");
+    }
 }
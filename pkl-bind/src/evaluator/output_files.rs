@@ -0,0 +1,150 @@
+use std::{collections::BTreeMap, fs, io, path::{Component, Path, PathBuf}};
+
+use similar::TextDiff;
+
+use crate::Error;
+
+/// Expression evaluating to the text of every file in `output.files`, keyed by path
+pub(crate) const OUTPUT_FILES_EXPR: &str = "output.files?.toMap()?.mapValues((_, it) -> it.text) ?? Map()";
+
+/// Decode the pkl `Map<String, String>` produced by [`OUTPUT_FILES_EXPR`]
+pub(crate) fn decode_output_files(data: &[u8]) -> Result<BTreeMap<String, String>, Error> {
+    let (_, files): (u8, BTreeMap<String, String>) = rmp_serde::from_slice(data)
+        .map_err(|err| Error::type_mismatch("Map<String, String>", err))?;
+    Ok(files)
+}
+
+/// How [`write_output_files`] treats the target directory
+#[derive(Debug, Default, Clone)]
+pub struct WriteOptions {
+    /// Report what would change without touching the file system
+    pub dry_run: bool,
+    /// Attach a unified diff to every created or modified file
+    pub diff: bool,
+}
+
+/// How an output file compares to what is already in the output directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Created,
+    Modified,
+    Unchanged,
+}
+
+/// What happened (or would happen, on a dry run) to one output file
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    /// Path of the file inside the output directory
+    pub path: PathBuf,
+    pub status: FileStatus,
+    /// Unified diff against the previous contents, if requested
+    pub diff: Option<String>,
+}
+
+/// Write the result of `evaluate_output_files` below `dir`, like `pkl eval -m`
+///
+/// Paths must be relative and may not leave `dir`; they are all checked before
+/// anything is written. Missing directories are created and files that already
+/// hold the same text are left alone.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::collections::BTreeMap;
+/// use pkl_bind::evaluator::output_files::{write_output_files, WriteOptions};
+///
+/// let files = BTreeMap::from([("app/config.yaml".to_string(), "port: 80\n".to_string())]);
+/// let opts = WriteOptions { dry_run: true, diff: true };
+///
+/// for change in write_output_files(&files, "out".as_ref(), &opts).unwrap() {
+///     println!("{:?} {}", change.status, change.path.display());
+/// }
+/// ```
+pub fn write_output_files(files: &BTreeMap<String, String>, dir: &Path, opts: &WriteOptions) -> Result<Vec<FileChange>, Error> {
+    // a bad path must not leave the directory half written
+    for name in files.keys() {
+        let relative = Path::new(name);
+        let escapes = relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            let source = io::Error::new(io::ErrorKind::InvalidInput, "output file path must stay inside the output directory");
+            return Err(Error::Io { path: relative.to_path_buf(), source });
+        }
+    }
+
+    let mut changes = vec![];
+
+    for (name, text) in files {
+        let relative = Path::new(name);
+        let path = dir.join(relative);
+        let previous = match fs::read_to_string(&path) {
+            Ok(previous) => Some(previous),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(source) => return Err(Error::Io { path, source }),
+        };
+
+        let status = match &previous {
+            None => FileStatus::Created,
+            Some(previous) if previous == text => FileStatus::Unchanged,
+            Some(_) => FileStatus::Modified,
+        };
+
+        let diff = (opts.diff && status != FileStatus::Unchanged).then(|| {
+            TextDiff::from_lines(previous.as_deref().unwrap_or_default(), text)
+                .unified_diff()
+                .header(&format!("a/{name}"), &format!("b/{name}"))
+                .to_string()
+        });
+
+        if !opts.dry_run && status != FileStatus::Unchanged {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|source| Error::Io { path: parent.to_path_buf(), source })?;
+            }
+            fs::write(&path, text).map_err(|source| Error::Io { path: path.clone(), source })?;
+        }
+
+        changes.push(FileChange { path: relative.to_path_buf(), status, diff });
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_output_files() {
+        let dir = std::env::temp_dir().join(format!("pkl-output-{}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("conf")).unwrap();
+        fs::write(dir.join("conf/a.yaml"), "port: 80\n").unwrap();
+        fs::write(dir.join("same.txt"), "same\n").unwrap();
+
+        let files = BTreeMap::from([
+            ("conf/a.yaml".to_string(), "port: 8080\n".to_string()),
+            ("conf/new/b.json".to_string(), "{}\n".to_string()),
+            ("same.txt".to_string(), "same\n".to_string()),
+        ]);
+
+        let dry = write_output_files(&files, &dir, &WriteOptions { dry_run: true, diff: true }).unwrap();
+        let statuses: Vec<FileStatus> = dry.iter().map(|c| c.status).collect();
+        assert_eq!(statuses, [FileStatus::Modified, FileStatus::Created, FileStatus::Unchanged]);
+        assert!(dry[0].diff.as_deref().unwrap().contains("-port: 80\n+port: 8080\n"));
+        assert!(dry[2].diff.is_none());
+        assert!(!dir.join("conf/new/b.json").exists());
+
+        let written = write_output_files(&files, &dir, &WriteOptions::default()).unwrap();
+        assert!(written.iter().all(|c| c.diff.is_none()));
+        assert_eq!(fs::read_to_string(dir.join("conf/a.yaml")).unwrap(), "port: 8080\n");
+        assert_eq!(fs::read_to_string(dir.join("conf/new/b.json")).unwrap(), "{}\n");
+
+        let escaping = BTreeMap::from([
+            ("fine.txt".to_string(), "fine\n".to_string()),
+            ("nested/../../evil".to_string(), String::new()),
+        ]);
+        assert!(matches!(write_output_files(&escaping, &dir, &WriteOptions::default()), Err(Error::Io { .. })));
+        // nothing was written, not even the files before the bad one
+        assert!(!dir.join("fine.txt").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
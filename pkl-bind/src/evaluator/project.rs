@@ -0,0 +1,400 @@
+//! Loading the dependency graph of a `PklProject` and its lock file
+//!
+//! `PklProject.deps.json` records the exact version every dependency resolved
+//! to, keyed by the dependency's package URI without minor and patch version:
+//!
+//! ```json
+//! {
+//!   "schemaVersion": 1,
+//!   "resolvedDependencies": {
+//!     "package://example.com/birds@0": {
+//!       "type": "remote",
+//!       "uri": "projectpackage://example.com/birds@0.5.0",
+//!       "checksums": {
+//!         "sha256": "8ee9e2c3..."
+//!       }
+//!     },
+//!     "package://example.com/fruit@1": {
+//!       "type": "local",
+//!       "uri": "projectpackage://example.com/fruit@1.0.0",
+//!       "path": "../fruit"
+//!     }
+//!   }
+//! }
+//! ```
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Component, Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::Error;
+
+use super::msg_api::outgoing::{Checksums, ProjectOrDependency};
+
+/// Name of the lock file next to `PklProject`
+pub const DEPS_FILE: &str = "PklProject.deps.json";
+
+/// The `schemaVersion` this crate reads and writes
+pub const DEPS_SCHEMA_VERSION: u32 = 1;
+
+/// Expression rendering the project module and its declared dependencies as
+/// JSON in the shape of [`ProjectOrDependency`]
+///
+/// Local dependencies are projects themselves, the converters are applied to
+/// them recursively.
+pub(crate) const PROJECT_EXPR: &str = r#"let (project = import("pkl:Project"))
+  new JsonRenderer {
+    converters {
+      [project.getClass()] = (it) -> new Dynamic {
+        type = "local"
+        packageUri = it.package?.uri
+        projectFileUri = it.projectFileUri
+        dependencies = it.dependencies
+      }
+      [project.RemoteDependency] = (it) -> new Dynamic {
+        type = "remote"
+        packageUri = it.uri
+        checksums = it.checksums
+      }
+    }
+  }.renderValue(module)"#;
+
+/// Decode the JSON produced by [`PROJECT_EXPR`]
+pub(crate) fn decode_project(json: &str) -> Result<ProjectOrDependency, Error> {
+    serde_json::from_str(json).map_err(|err| Error::type_mismatch("Project", err))
+}
+
+/// The contents of a `PklProject.deps.json` lock file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectDeps {
+    pub schema_version: u32,
+    /// Resolved dependencies keyed by their major version package URI
+    pub resolved_dependencies: BTreeMap<String, ResolvedDependency>,
+}
+
+/// A dependency as recorded in the lock file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ResolvedDependency {
+    /// A package downloaded from `uri`
+    Remote { uri: String, checksums: Checksums },
+    /// A project on disk, `path` is relative to the locking project
+    Local { uri: String, path: String },
+}
+
+impl Default for ProjectDeps {
+    fn default() -> Self {
+        Self { schema_version: DEPS_SCHEMA_VERSION, resolved_dependencies: Default::default() }
+    }
+}
+
+impl ProjectDeps {
+    /// Parse and validate the text of a lock file
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Header {
+            schema_version: u32,
+        }
+
+        // check the version first, later versions may not parse at all
+        let header: Header = serde_json::from_str(text).map_err(|err| Error::Project(format!("{DEPS_FILE}: {err}")))?;
+        if header.schema_version != DEPS_SCHEMA_VERSION {
+            return Err(Error::Project(format!(
+                "{DEPS_FILE} has schemaVersion {}, but only version {DEPS_SCHEMA_VERSION} is supported",
+                header.schema_version,
+            )));
+        }
+
+        let deps: ProjectDeps = serde_json::from_str(text).map_err(|err| Error::Project(format!("{DEPS_FILE}: {err}")))?;
+        deps.validate()?;
+        Ok(deps)
+    }
+
+    /// Read the lock file in `project_dir`
+    pub fn read(project_dir: &Path) -> Result<Self, Error> {
+        let path = project_dir.join(DEPS_FILE);
+        let text = fs::read_to_string(&path).map_err(|source| Error::Io { path, source })?;
+        Self::parse(&text)
+    }
+
+    /// The lock file text, formatted like `pkl project resolve` does
+    pub fn to_json(&self) -> String {
+        let mut text = serde_json::to_string_pretty(self).expect("lock file is always serializable");
+        text.push('\n');
+        text
+    }
+
+    /// Write the lock file into `project_dir`
+    pub fn write(&self, project_dir: &Path) -> Result<(), Error> {
+        self.validate()?;
+
+        let path = project_dir.join(DEPS_FILE);
+        fs::write(&path, self.to_json()).map_err(|source| Error::Io { path, source })
+    }
+
+    /// Check that keys are major version package URIs and checksums are sha256 digests
+    pub fn validate(&self) -> Result<(), Error> {
+        for (key, dep) in &self.resolved_dependencies {
+            let canonical = PackageUri::parse(key)?;
+            if canonical.version != canonical.major() {
+                return Err(Error::Project(format!("{DEPS_FILE}: `{key}` must only carry the major version")));
+            }
+
+            let (ResolvedDependency::Remote { uri, .. } | ResolvedDependency::Local { uri, .. }) = dep;
+            let resolved = PackageUri::parse(uri)?;
+            if resolved.name != canonical.name || resolved.major() != canonical.major() {
+                return Err(Error::Project(format!("{DEPS_FILE}: `{key}` resolves to the unrelated package `{uri}`")));
+            }
+
+            if let ResolvedDependency::Remote { checksums, .. } = dep {
+                let digest = &checksums.sha256;
+                if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+                    return Err(Error::Project(format!("{DEPS_FILE}: `{key}` has a malformed sha256 checksum `{digest}`")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every dependency declared in `project` is locked at its
+    /// declared version or later
+    pub fn check(&self, project: &ProjectOrDependency) -> Result<(), Error> {
+        for (name, dep) in &project.dependencies {
+            let Some(uri) = &dep.package_uri else { continue };
+            let declared = PackageUri::parse(uri)?;
+
+            let (ResolvedDependency::Remote { uri: resolved_uri, .. } | ResolvedDependency::Local { uri: resolved_uri, .. }) = self.resolve(name, uri)?;
+            let locked = PackageUri::parse(resolved_uri)?;
+            if locked.version_key() < declared.version_key() {
+                return Err(Error::Project(format!(
+                    "dependency `{name}` requires version {} but {DEPS_FILE} locks version {}, run `pkl project resolve`",
+                    declared.version, locked.version,
+                )));
+            }
+
+            self.check(dep)?;
+        }
+        Ok(())
+    }
+
+    /// The dependencies declared in `project` at the versions locked here, in
+    /// the shape sent in `CreateEvaluator.project`
+    ///
+    /// Entries are keyed by the names `project` declares them under, and local
+    /// dependencies carry their own dependencies resolved the same way. Local
+    /// paths are resolved against `project_dir`.
+    pub fn to_message(&self, project: &ProjectOrDependency, project_dir: &Path) -> Result<HashMap<String, ProjectOrDependency>, Error> {
+        let mut result = HashMap::new();
+
+        for (name, dep) in &project.dependencies {
+            let Some(uri) = &dep.package_uri else { continue };
+
+            let mut message = self.resolve(name, uri)?.to_message(project_dir);
+            if dep.r#type == "local" {
+                message.dependencies = self.to_message(dep, project_dir)?;
+            }
+            result.insert(name.clone(), message);
+        }
+        Ok(result)
+    }
+
+    /// The locked entry for the dependency `name` declared as `uri`
+    fn resolve(&self, name: &str, uri: &str) -> Result<&ResolvedDependency, Error> {
+        let key = PackageUri::parse(uri)?.canonical();
+        self.resolved_dependencies.get(&key).ok_or_else(|| Error::Project(format!(
+            "dependency `{name}` ({uri}) is missing from {DEPS_FILE}, run `pkl project resolve`"
+        )))
+    }
+}
+
+impl ResolvedDependency {
+    /// This dependency in the wire format, local paths are resolved against `project_dir`
+    pub fn to_message(&self, project_dir: &Path) -> ProjectOrDependency {
+        match self {
+            ResolvedDependency::Remote { uri, checksums } => ProjectOrDependency {
+                package_uri: Some(uri.clone()),
+                r#type: "remote".into(),
+                project_file_uri: None,
+                checksums: Some(checksums.clone()),
+                dependencies: Default::default(),
+            },
+            ResolvedDependency::Local { uri, path } => {
+                let project_file = normalize_path(&project_dir.join(path).join("PklProject"));
+                let project_file_uri = Url::from_file_path(&project_file)
+                    .map(String::from)
+                    .unwrap_or_else(|()| format!("file://{}", project_file.display()));
+
+                ProjectOrDependency {
+                    package_uri: Some(uri.clone()),
+                    r#type: "local".into(),
+                    project_file_uri: Some(project_file_uri),
+                    checksums: None,
+                    dependencies: Default::default(),
+                }
+            }
+        }
+    }
+}
+
+/// `path` without `.` and `..` segments, resolved lexically since the
+/// dependency doesn't have to exist on this machine
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push(component);
+                }
+            },
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+/// A `package:` or `projectpackage:` URI split into name and version
+struct PackageUri<'a> {
+    /// Authority and path, e.g. `example.com/birds`
+    name: &'a str,
+    version: &'a str,
+}
+
+impl<'a> PackageUri<'a> {
+    fn parse(uri: &'a str) -> Result<Self, Error> {
+        let invalid = || Error::Project(format!("`{uri}` is not a package URI"));
+
+        let rest = uri.strip_prefix("package://")
+            .or_else(|| uri.strip_prefix("projectpackage://"))
+            .ok_or_else(invalid)?;
+        // drop a `::sha256:...` checksum suffix
+        let rest = rest.split("::").next().unwrap_or(rest);
+        let (name, version) = rest.rsplit_once('@').ok_or_else(invalid)?;
+        if name.is_empty() || version.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self { name, version })
+    }
+
+    fn major(&self) -> &'a str {
+        self.version.split(['.', '-', '+']).next().unwrap_or(self.version)
+    }
+
+    /// The lock file key, e.g. `package://example.com/birds@0`
+    fn canonical(&self) -> String {
+        format!("package://{}@{}", self.name, self.major())
+    }
+
+    /// Major, minor and patch for comparison, ignoring pre-release and build metadata
+    fn version_key(&self) -> [u64; 3] {
+        let core = self.version.split(['-', '+']).next().unwrap_or(self.version);
+        let mut parts = core.split('.').map(|part| part.parse().unwrap_or(0));
+        [0; 3].map(|_| parts.next().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA: &str = "8ee9e2c3d0e8c7ba3f7b1c0d1c7d2f4e5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d";
+
+    fn lock_file() -> String {
+        format!(r#"{{
+  "schemaVersion": 1,
+  "resolvedDependencies": {{
+    "package://example.com/birds@0": {{
+      "type": "remote",
+      "uri": "projectpackage://example.com/birds@0.5.0",
+      "checksums": {{
+        "sha256": "{SHA}"
+      }}
+    }},
+    "package://example.com/fruit@1": {{
+      "type": "local",
+      "uri": "projectpackage://example.com/fruit@1.0.0",
+      "path": "../fruit"
+    }}
+  }}
+}}
+"#)
+    }
+
+    fn declared(uri: &str) -> ProjectOrDependency {
+        let dep = ProjectOrDependency {
+            package_uri: Some(uri.into()),
+            r#type: "remote".into(),
+            project_file_uri: None,
+            checksums: None,
+            dependencies: Default::default(),
+        };
+        ProjectOrDependency {
+            package_uri: None,
+            r#type: "local".into(),
+            project_file_uri: None,
+            checksums: None,
+            dependencies: HashMap::from([("birds".to_string(), dep)]),
+        }
+    }
+
+    #[test]
+    fn test_parse_deps() {
+        let deps = ProjectDeps::parse(&lock_file()).unwrap();
+        assert_eq!(deps.to_json(), lock_file());
+
+        let mut project = declared("package://example.com/birds@0.4.2");
+        let mut fruit = declared("package://example.com/birds@0.5.0");
+        fruit.r#type = "local".into();
+        fruit.package_uri = Some("package://example.com/fruit@1.0.0".into());
+        project.dependencies.insert("fruit".into(), fruit);
+
+        let message = deps.to_message(&project, Path::new("/work/app")).unwrap();
+        let birds = &message["birds"];
+        assert_eq!(birds.r#type, "remote");
+        assert_eq!(birds.package_uri.as_deref(), Some("projectpackage://example.com/birds@0.5.0"));
+        assert_eq!(birds.checksums.as_ref().unwrap().sha256, SHA);
+        let fruit = &message["fruit"];
+        assert_eq!(fruit.project_file_uri.as_deref(), Some("file:///work/fruit/PklProject"));
+        assert_eq!(fruit.dependencies["birds"].package_uri.as_deref(), Some("projectpackage://example.com/birds@0.5.0"));
+        let err = deps.to_message(&declared("package://example.com/birds@1.0.0"), Path::new("/work/app")).unwrap_err();
+        assert!(err.to_string().contains("missing from PklProject.deps.json"), "{err}");
+
+        deps.check(&declared("package://example.com/birds@0.4.2")).unwrap();
+        let err = deps.check(&declared("package://example.com/birds@0.6.0")).unwrap_err();
+        assert!(err.to_string().contains("requires version 0.6.0 but PklProject.deps.json locks version 0.5.0"), "{err}");
+        let err = deps.check(&declared("package://example.com/birds@1.0.0")).unwrap_err();
+        assert!(err.to_string().contains("missing from PklProject.deps.json"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid_deps() {
+        let err = ProjectDeps::parse(&lock_file().replace("\"schemaVersion\": 1", "\"schemaVersion\": 2")).unwrap_err();
+        assert!(err.to_string().contains("schemaVersion 2, but only version 1"), "{err}");
+
+        let err = ProjectDeps::parse(&lock_file().replace(SHA, "abc")).unwrap_err();
+        assert!(err.to_string().contains("malformed sha256"), "{err}");
+
+        let err = ProjectDeps::parse(&lock_file().replace("\"sha256\"", "\"md5\"")).unwrap_err();
+        assert!(matches!(err, Error::Project(_)));
+
+        let err = ProjectDeps::parse(&lock_file().replace("birds@0.5.0", "birds@1.0.0")).unwrap_err();
+        assert!(err.to_string().contains("unrelated package"), "{err}");
+    }
+
+    #[test]
+    fn test_write_deps() {
+        let dir = std::env::temp_dir().join(format!("pkl-project-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let deps = ProjectDeps::parse(&lock_file()).unwrap();
+        deps.write(&dir).unwrap();
+        assert_eq!(ProjectDeps::read(&dir).unwrap(), deps);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Client side readers that serve custom URI schemes to pkl
+//!
+//! Readers are registered on
+//! [`EvaluatorOptions`](super::evaluator_options::EvaluatorOptions) and are
+//! announced to the server when the evaluator is created. The server then
+//! calls back into the client whenever a module with the reader's scheme is
+//! imported, read or listed.
+//!
+//! Callbacks are answered on the executor's reader thread (or task), which
+//! reads nothing else from the server until the reader returns. A reader
+//! must therefore not evaluate on the executor it serves.
+
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod embedded;
+#[cfg(feature = "git")]
+pub mod git;
+pub mod memory_fs;
+
+use std::{collections::BTreeSet, io, sync::Arc};
+
+use url::Url;
+
+use super::msg_api::{incoming::{ListModules, ListResources, ReadModule, ReadResource}, outgoing::{ListModulesResponse, ListResourceResponse, ModuleReader, OutgoingMessage, PathElement, ReadModuleResponse, ReadResourceResponse, ResourceReader}};
+
+/// Serves pkl modules for the URI scheme [`ModuleReaderImpl::scheme`]
+///
+/// # Example
+///
+/// ```
+/// use std::io;
+/// use url::Url;
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, msg_api::outgoing::PathElement, reader::ModuleReaderImpl};
+///
+/// struct Greetings;
+///
+/// impl ModuleReaderImpl for Greetings {
+///     fn scheme(&self) -> &str { "greetings" }
+///     fn is_local(&self) -> bool { true }
+///     fn has_hierarchical_uris(&self) -> bool { false }
+///     fn is_globbable(&self) -> bool { false }
+///
+///     fn read(&self, uri: &Url) -> io::Result<String> {
+///         Ok(format!("greeting = \"hello {}\"", uri.path()))
+///     }
+///
+///     fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+///         Ok(vec![])
+///     }
+/// }
+///
+/// // `import "greetings:world"` is now answered by `Greetings`
+/// let opts = EvaluatorOptions::preconfigured().with_module_reader(Greetings);
+/// ```
+pub trait ModuleReaderImpl: Send + Sync {
+    /// The URI scheme this reader is responsible for, without the `:`
+    fn scheme(&self) -> &str;
+
+    /// Whether modules are local, which allows relative triple-dot imports
+    fn is_local(&self) -> bool;
+
+    /// Whether URIs are hierarchical like `scheme:/a/b.pkl` and can be resolved relative to each other
+    fn has_hierarchical_uris(&self) -> bool;
+
+    /// Whether `import*` may glob over URIs of this scheme, which requires [`ModuleReaderImpl::list`]
+    fn is_globbable(&self) -> bool;
+
+    /// The source text of the module at `uri`
+    fn read(&self, uri: &Url) -> io::Result<String>;
+
+    /// The elements directly below `uri`, used when globbing
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>>;
+}
+
+impl dyn ModuleReaderImpl {
+    /// The wire descriptor sent in `CreateEvaluator`
+    pub(crate) fn descriptor(&self) -> ModuleReader {
+        ModuleReader {
+            scheme: self.scheme().to_string(),
+            has_hierarchical_uris: self.has_hierarchical_uris(),
+            is_globbable: self.is_globbable(),
+            is_local: self.is_local(),
+        }
+    }
+}
+
+/// Serves resources read with `read("scheme:...")` for the URI scheme [`ResourceReaderImpl::scheme`]
+///
+/// # Example
+///
+/// ```
+/// use std::{collections::HashMap, io};
+/// use url::Url;
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, msg_api::outgoing::PathElement, reader::ResourceReaderImpl};
+///
+/// struct Secrets(HashMap<String, String>);
+///
+/// impl ResourceReaderImpl for Secrets {
+///     fn scheme(&self) -> &str { "secret" }
+///     fn has_hierarchical_uris(&self) -> bool { false }
+///     fn is_globbable(&self) -> bool { false }
+///
+///     fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+///         self.0.get(uri.path())
+///             .map(|secret| secret.clone().into_bytes())
+///             .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown secret {uri}")))
+///     }
+///
+///     fn list(&self, _uri: &Url) -> io::Result<Vec<PathElement>> {
+///         Ok(vec![])
+///     }
+/// }
+///
+/// // `read("secret:db-password").text` is now answered by `Secrets`
+/// let secrets = Secrets(HashMap::from([("db-password".into(), "hunter2".into())]));
+/// let opts = EvaluatorOptions::preconfigured().with_resource_reader(secrets);
+/// ```
+pub trait ResourceReaderImpl: Send + Sync {
+    /// The URI scheme this reader is responsible for, without the `:`
+    fn scheme(&self) -> &str;
+
+    /// Whether URIs are hierarchical like `scheme:/a/b.txt`, which is required for globbing
+    fn has_hierarchical_uris(&self) -> bool;
+
+    /// Whether `read*` may glob over URIs of this scheme, which requires [`ResourceReaderImpl::list`]
+    fn is_globbable(&self) -> bool;
+
+    /// The contents of the resource at `uri`
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>>;
+
+    /// The elements directly below `uri`, used when globbing
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>>;
+}
+
+impl dyn ResourceReaderImpl {
+    /// The wire descriptor sent in `CreateEvaluator`
+    pub(crate) fn descriptor(&self) -> ResourceReader {
+        ResourceReader {
+            scheme: self.scheme().to_string(),
+            has_hierarchical_uris: self.has_hierarchical_uris(),
+            is_globbable: self.is_globbable(),
+        }
+    }
+}
+
+/// `path` as an absolute path without empty or `.` segments
+pub(crate) fn normalize(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty() && *s != ".").collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// The entries directly below `dir` in a tree made up of the normalized file `paths`
+///
+/// Directories exist implicitly for every file below them.
+pub(crate) fn list_children<'a>(paths: impl IntoIterator<Item = &'a str>, dir: &str) -> Vec<PathElement> {
+    let dir = format!("{}/", normalize(dir).trim_end_matches('/'));
+
+    let entries: BTreeSet<(&str, bool)> = paths.into_iter()
+        .filter_map(|path| path.strip_prefix(&dir))
+        .map(|rest| match rest.split_once('/') {
+            Some((name, _)) => (name, true),
+            None => (rest, false),
+        })
+        .collect();
+
+    entries.into_iter()
+        .map(|(name, is_directory)| PathElement { name: name.to_string(), is_directory })
+        .collect()
+}
+
+/// Whether `dir` holds any of the normalized file `paths`
+pub(crate) fn is_directory<'a>(paths: impl IntoIterator<Item = &'a str>, dir: &str) -> bool {
+    let dir = format!("{}/", normalize(dir).trim_end_matches('/'));
+    paths.into_iter().any(|path| path.starts_with(&dir))
+}
+
+/// The reader among `readers` responsible for the scheme of `uri`
+fn find_reader<'a, R: ?Sized>(readers: &'a [Arc<R>], scheme: impl Fn(&R) -> &str, uri: &str) -> Result<(&'a R, Url), String> {
+    let url = Url::parse(uri).map_err(|err| format!("Invalid URI {uri}: {err}"))?;
+    let reader = readers.iter()
+        .find(|reader| scheme(reader) == url.scheme())
+        .ok_or_else(|| format!("No reader registered for {uri}"))?;
+    Ok((reader, url))
+}
+
+/// Answer a `ReadModule` request with the matching reader
+pub(crate) fn read_module(readers: &[Arc<dyn ModuleReaderImpl>], evaluator_id: i64, req: ReadModule) -> OutgoingMessage {
+    let result = find_reader(readers, |r| r.scheme(), &req.uri)
+        .and_then(|(reader, url)| reader.read(&url).map_err(|err| err.to_string()));
+
+    let (contents, error) = match result {
+        Ok(contents) => (Some(contents), None),
+        Err(error) => (None, Some(error)),
+    };
+
+    OutgoingMessage::ReadModuleResponse(ReadModuleResponse { request_id: req.request_id, evaluator_id, contents, error })
+}
+
+/// Answer a `ListModules` request with the matching reader
+pub(crate) fn list_modules(readers: &[Arc<dyn ModuleReaderImpl>], evaluator_id: i64, req: ListModules) -> OutgoingMessage {
+    let result = find_reader(readers, |r| r.scheme(), &req.uri)
+        .and_then(|(reader, url)| reader.list(&url).map_err(|err| err.to_string()));
+
+    let (path_elements, error) = match result {
+        Ok(elements) => (Some(elements), None),
+        Err(error) => (None, Some(error)),
+    };
+
+    OutgoingMessage::ListModulesResponse(ListModulesResponse { request_id: req.request_id, evaluator_id, path_elements, error })
+}
+
+/// Answer a `ReadResource` request with the matching reader
+pub(crate) fn read_resource(readers: &[Arc<dyn ResourceReaderImpl>], evaluator_id: i64, req: ReadResource) -> OutgoingMessage {
+    let result = find_reader(readers, |r| r.scheme(), &req.uri)
+        .and_then(|(reader, url)| reader.read(&url).map_err(|err| err.to_string()));
+
+    let (contents, error) = match result {
+        Ok(contents) => (Some(contents), None),
+        Err(error) => (None, Some(error)),
+    };
+
+    OutgoingMessage::ReadResourceResponse(ReadResourceResponse { request_id: req.request_id, evaluator_id, contents, error })
+}
+
+/// Answer a `ListResources` request with the matching reader
+pub(crate) fn list_resources(readers: &[Arc<dyn ResourceReaderImpl>], evaluator_id: i64, req: ListResources) -> OutgoingMessage {
+    let result = find_reader(readers, |r| r.scheme(), &req.uri)
+        .and_then(|(reader, url)| reader.list(&url).map_err(|err| err.to_string()));
+
+    let (path_elements, error) = match result {
+        Ok(elements) => (Some(elements), None),
+        Err(error) => (None, Some(error)),
+    };
+
+    OutgoingMessage::ListResourceResponse(ListResourceResponse { request_id: req.request_id, evaluator_id, path_elements, error })
+}
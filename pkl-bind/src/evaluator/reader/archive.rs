@@ -0,0 +1,232 @@
+use std::{fs::File, io::{self, Read, Seek}, path::{Component, Path}};
+
+use flate2::read::GzDecoder;
+use url::Url;
+
+use crate::{evaluator::msg_api::outgoing::PathElement, Error};
+
+use super::{memory_fs::MemoryFs, ModuleReaderImpl, ResourceReaderImpl};
+
+/// Archive formats understood by [`ArchiveFs`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The format matching the extension of `path`: `.tar`, `.tar.gz`, `.tgz` or `.zip`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// The files of a tar or zip archive served as modules and resources
+///
+/// The archive is read once when it is mounted, later changes to the file
+/// are not seen. Paths are hierarchical, `bundle:/config/app.pkl` is the
+/// entry `config/app.pkl`, and directories can be globbed.
+///
+/// # Example
+///
+/// ```no_run
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, reader::archive::ArchiveFs};
+///
+/// let bundle = ArchiveFs::open("bundle", "config-bundle.tar.gz").expect("failed to read the bundle");
+///
+/// // modules can now `import "bundle:/config/app.pkl"`
+/// let opts = EvaluatorOptions::preconfigured()
+///     .with_module_reader(bundle.clone())
+///     .with_resource_reader(bundle);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ArchiveFs {
+    fs: MemoryFs,
+}
+
+impl ArchiveFs {
+    /// Mount the archive at `path` under `scheme`, picking the format from the extension
+    pub fn open(scheme: impl Into<String>, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let io_error = |source| Error::Io { path: path.to_path_buf(), source };
+
+        let format = ArchiveFormat::from_path(path)
+            .ok_or_else(|| io_error(io::Error::new(io::ErrorKind::InvalidInput, "expected a .tar, .tar.gz, .tgz or .zip archive")))?;
+        let file = File::open(path).map_err(io_error)?;
+
+        Self::from_reader(scheme, file, format).map_err(io_error)
+    }
+
+    /// Mount the archive read from `reader` under `scheme`
+    pub fn from_reader(scheme: impl Into<String>, reader: impl Read + Seek, format: ArchiveFormat) -> io::Result<Self> {
+        let fs = MemoryFs::new(scheme);
+
+        match format {
+            ArchiveFormat::Tar => read_tar(&fs, reader)?,
+            ArchiveFormat::TarGz => read_tar(&fs, GzDecoder::new(reader))?,
+            ArchiveFormat::Zip => read_zip(&fs, reader)?,
+        }
+
+        Ok(Self { fs })
+    }
+}
+
+/// Whether `path` stays inside the archive root
+fn is_contained(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Capacity to reserve for an entry, the size in the header is not trusted
+fn size_hint(size: u64) -> usize {
+    size.min(1 << 20) as usize
+}
+
+fn read_tar(fs: &MemoryFs, reader: impl Read) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        if !is_contained(&path) {
+            continue;
+        }
+
+        let mut contents = Vec::with_capacity(size_hint(entry.size()));
+        entry.read_to_end(&mut contents)?;
+        fs.insert(&path.to_string_lossy(), contents);
+    }
+    Ok(())
+}
+
+fn read_zip(fs: &MemoryFs, reader: impl Read + Seek) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        // `enclosed_name` rejects absolute paths and `..`
+        let Some(path) = entry.enclosed_name() else { continue };
+
+        let mut contents = Vec::with_capacity(size_hint(entry.size()));
+        entry.read_to_end(&mut contents)?;
+        fs.insert(&path.to_string_lossy(), contents);
+    }
+    Ok(())
+}
+
+impl ModuleReaderImpl for ArchiveFs {
+    fn scheme(&self) -> &str {
+        ModuleReaderImpl::scheme(&self.fs)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<String> {
+        ModuleReaderImpl::read(&self.fs, uri)
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        ModuleReaderImpl::list(&self.fs, uri)
+    }
+}
+
+impl ResourceReaderImpl for ArchiveFs {
+    fn scheme(&self) -> &str {
+        ResourceReaderImpl::scheme(&self.fs)
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        ResourceReaderImpl::read(&self.fs, uri)
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        ResourceReaderImpl::list(&self.fs, uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const FILES: [(&str, &str); 3] = [("config/app.pkl", "port = 8080"), ("config/db.pkl", "host = \"db\""), ("README.md", "# bundle")];
+
+    fn tar_gz() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, text) in FILES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, text.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn zip() -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, text) in FILES {
+            writer.start_file(path, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(text.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_archive_fs() {
+        let url = |uri: &str| Url::parse(uri).unwrap();
+
+        for (format, data) in [(ArchiveFormat::TarGz, tar_gz()), (ArchiveFormat::Zip, zip())] {
+            let fs = ArchiveFs::from_reader("bundle", Cursor::new(data), format).unwrap();
+
+            assert_eq!(ModuleReaderImpl::read(&fs, &url("bundle:/config/app.pkl")).unwrap(), "port = 8080");
+            assert_eq!(ResourceReaderImpl::read(&fs, &url("bundle:/README.md")).unwrap(), b"# bundle");
+            assert!(ModuleReaderImpl::read(&fs, &url("bundle:/missing.pkl")).is_err());
+
+            let names: Vec<String> = ModuleReaderImpl::list(&fs, &url("bundle:/config/")).unwrap().into_iter().map(|e| e.name).collect();
+            assert_eq!(names, ["app.pkl", "db.pkl"], "{format:?}");
+        }
+
+        assert_eq!(ArchiveFormat::from_path(Path::new("a/bundle.TGZ")), Some(ArchiveFormat::TarGz));
+        assert_eq!(ArchiveFormat::from_path(Path::new("bundle.tar")), Some(ArchiveFormat::Tar));
+        assert!(matches!(ArchiveFs::open("bundle", "bundle.rar"), Err(Error::Io { .. })));
+    }
+}
@@ -0,0 +1,113 @@
+use std::io;
+
+use url::Url;
+
+use crate::evaluator::msg_api::outgoing::PathElement;
+
+use super::{is_directory, list_children, normalize, ModuleReaderImpl};
+
+/// Scheme used by [`EmbeddedFs::new`]
+pub const DEFAULT_SCHEME: &str = "embedded";
+
+/// pkl modules compiled into the binary, served under `embedded:`
+///
+/// Built by `embed_pkl!`, re-exported at the crate root with the `derive`
+/// feature, which embeds every `.pkl` file below a directory of the calling
+/// crate. Paths are hierarchical and relative to that
+/// directory, so `embedded:/schemas/App.pkl` is the file `schemas/App.pkl`.
+///
+/// # Example
+///
+/// ```ignore
+/// use pkl_bind::evaluator::evaluator_options::EvaluatorOptions;
+/// use pkl_bind::embed_pkl;
+///
+/// // every .pkl file below `<crate>/pkl`
+/// let schemas = embed_pkl!("pkl");
+/// let opts = EvaluatorOptions::hermetic().with_module_reader(schemas);
+/// ```
+#[derive(Debug, Clone)]
+pub struct EmbeddedFs {
+    scheme: String,
+    files: &'static [(&'static str, &'static str)],
+}
+
+impl EmbeddedFs {
+    /// Serve `files`, pairs of path and source text, under [`DEFAULT_SCHEME`]
+    pub fn new(files: &'static [(&'static str, &'static str)]) -> Self {
+        Self { scheme: DEFAULT_SCHEME.to_string(), files }
+    }
+
+    /// Serve the files under `scheme` instead
+    pub fn with_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// Paths of the embedded files, relative to the embedded directory
+    pub fn paths(&self) -> impl Iterator<Item = &'static str> {
+        self.files.iter().map(|(path, _)| *path)
+    }
+
+    fn normalized_paths(&self) -> Vec<String> {
+        self.paths().map(normalize).collect()
+    }
+}
+
+impl ModuleReaderImpl for EmbeddedFs {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<String> {
+        let path = normalize(uri.path());
+
+        if let Some((_, text)) = self.files.iter().find(|(file, _)| normalize(file) == path) {
+            return Ok(text.to_string());
+        }
+        if is_directory(self.normalized_paths().iter().map(String::as_str), &path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{uri} is a directory")));
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{uri} is not embedded")))
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        Ok(list_children(self.normalized_paths().iter().map(String::as_str), uri.path()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkl_derive::embed_pkl;
+
+    use super::*;
+
+    #[test]
+    fn test_embedded_fs() {
+        let fs = embed_pkl!("tests/embedded");
+        let url = |uri: &str| Url::parse(uri).unwrap();
+
+        assert_eq!(fs.paths().collect::<Vec<_>>(), ["birds/Bird.pkl", "birds/pigeon.pkl", "config.pkl"]);
+        assert!(fs.read(&url("embedded:/birds/pigeon.pkl")).unwrap().contains("name = \"Pigeon\""));
+        assert_eq!(fs.read(&url("embedded:/birds")).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs.read(&url("embedded:/notes.txt")).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let names: Vec<(String, bool)> = fs.list(&url("embedded:/")).unwrap().into_iter().map(|e| (e.name, e.is_directory)).collect();
+        assert_eq!(names, [("birds".to_string(), true), ("config.pkl".to_string(), false)]);
+
+        let fs = fs.with_scheme("schemas");
+        assert_eq!(ModuleReaderImpl::scheme(&fs), "schemas");
+    }
+}
@@ -0,0 +1,227 @@
+use std::{io, path::Path};
+
+use url::Url;
+
+use crate::{evaluator::msg_api::outgoing::PathElement, Error};
+
+use super::{normalize, ModuleReaderImpl, ResourceReaderImpl};
+
+/// Scheme used by [`GitFs::open`]
+pub const DEFAULT_SCHEME: &str = "git";
+
+/// Files of a local git repository as they were at some revision
+///
+/// `git:/config/app.pkl?rev=abc123` reads `config/app.pkl` from the commit,
+/// branch or tag `abc123`. URIs without a `rev` use the reader's default,
+/// `HEAD` unless changed with [`GitFs::with_rev`]. pkl drops the query when
+/// it resolves relative imports, so a module that imports its neighbours
+/// should be evaluated with the default revision set instead.
+///
+/// # Example
+///
+/// ```no_run
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, module_source::uri_source, reader::git::GitFs};
+/// use url::Url;
+///
+/// let repo = GitFs::open(".").expect("not a git repository").with_rev("v1.2.0");
+/// let opts = EvaluatorOptions::preconfigured().with_module_reader(repo);
+///
+/// let source = uri_source(Url::parse("git:/config/app.pkl").unwrap());
+/// ```
+#[derive(Clone)]
+pub struct GitFs {
+    scheme: String,
+    rev: String,
+    repo: gix::ThreadSafeRepository,
+}
+
+impl GitFs {
+    /// Serve the repository at `path` under `git:` at `HEAD`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let repo = gix::ThreadSafeRepository::open(path)
+            .map_err(|err| Error::Io { path: path.to_path_buf(), source: io::Error::other(err) })?;
+
+        Ok(Self { scheme: DEFAULT_SCHEME.to_string(), rev: "HEAD".to_string(), repo })
+    }
+
+    /// Serve the repository under `scheme` instead
+    pub fn with_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// Read URIs without a `rev` query at `rev`, any revision `git rev-parse` accepts
+    pub fn with_rev(mut self, rev: impl Into<String>) -> Self {
+        self.rev = rev.into();
+        self
+    }
+
+    /// The contents of the file at `uri`
+    fn read_bytes(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        let repo = self.repo.to_thread_local();
+        let tree = self.tree(&repo, uri)?;
+
+        let path = relative_path(uri);
+        let entry = tree.lookup_entry_by_path(&path).map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{uri} not found")))?;
+        if entry.mode().is_tree() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{uri} is a directory")));
+        }
+
+        let blob = entry.object().map_err(io::Error::other)?.detach();
+        Ok(blob.data)
+    }
+
+    /// The entries of the directory at `uri`
+    fn list_elements(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        let repo = self.repo.to_thread_local();
+        let mut tree = self.tree(&repo, uri)?;
+
+        let path = relative_path(uri);
+        if !path.is_empty() {
+            let entry = tree.lookup_entry_by_path(&path).map_err(io::Error::other)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{uri} not found")))?;
+            tree = entry.object().map_err(io::Error::other)?.peel_to_tree().map_err(io::Error::other)?;
+        }
+
+        tree.iter()
+            .map(|entry| {
+                let entry = entry.map_err(io::Error::other)?;
+                Ok(PathElement { name: entry.filename().to_string(), is_directory: entry.mode().is_tree() })
+            })
+            .collect()
+    }
+
+    /// The root tree of the revision requested by `uri`
+    fn tree<'repo>(&self, repo: &'repo gix::Repository, uri: &Url) -> io::Result<gix::Tree<'repo>> {
+        let rev = uri.query_pairs()
+            .find(|(key, _)| key == "rev")
+            .map(|(_, rev)| rev.into_owned())
+            .unwrap_or_else(|| self.rev.clone());
+
+        let id = repo.rev_parse_single(rev.as_str())
+            .map_err(|err| io::Error::new(io::ErrorKind::NotFound, format!("unknown revision {rev}: {err}")))?;
+        id.object().map_err(io::Error::other)?.peel_to_tree().map_err(io::Error::other)
+    }
+}
+
+/// The path of `uri` relative to the repository root, empty for the root
+fn relative_path(uri: &Url) -> String {
+    normalize(uri.path()).trim_start_matches('/').to_string()
+}
+
+impl std::fmt::Debug for GitFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitFs")
+            .field("scheme", &self.scheme)
+            .field("rev", &self.rev)
+            .field("repo", &self.repo.path())
+            .finish()
+    }
+}
+
+impl ModuleReaderImpl for GitFs {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<String> {
+        String::from_utf8(self.read_bytes(uri)?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{uri} is not valid UTF-8")))
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        self.list_elements(uri)
+    }
+}
+
+impl ResourceReaderImpl for GitFs {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        self.read_bytes(uri)
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        self.list_elements(uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+            .args(["-c", "user.name=pkl", "-c", "user.email=pkl@example.com", "-c", "commit.gpgsign=false"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .expect("Failed to run git");
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_git_fs() {
+        let dir = std::env::temp_dir().join(format!("pkl-git-{}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("config")).unwrap();
+        git(&dir, &["init", "-q"]);
+
+        fs::write(dir.join("config/app.pkl"), "port = 80").unwrap();
+        git(&dir, &["add", "."]);
+        git(&dir, &["commit", "-q", "-m", "first"]);
+        let first = git(&dir, &["rev-parse", "HEAD"]);
+
+        fs::write(dir.join("config/app.pkl"), "port = 8080").unwrap();
+        fs::write(dir.join("config/db.pkl"), "host = \"db\"").unwrap();
+        git(&dir, &["add", "."]);
+        git(&dir, &["commit", "-q", "-m", "second"]);
+
+        let repo = GitFs::open(&dir).unwrap();
+        let url = |uri: &str| Url::parse(uri).unwrap();
+
+        assert_eq!(ModuleReaderImpl::read(&repo, &url("git:/config/app.pkl")).unwrap(), "port = 8080");
+        let at_first = url(&format!("git:/config/app.pkl?rev={first}"));
+        assert_eq!(ModuleReaderImpl::read(&repo, &at_first).unwrap(), "port = 80");
+        assert_eq!(ModuleReaderImpl::read(&repo.clone().with_rev("HEAD~1"), &url("git:/config/app.pkl")).unwrap(), "port = 80");
+
+        assert_eq!(ModuleReaderImpl::read(&repo, &url("git:/config")).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(ModuleReaderImpl::read(&repo, &url("git:/missing.pkl")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(ModuleReaderImpl::read(&repo, &url("git:/config/app.pkl?rev=nope")).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let names = |uri: &str| -> Vec<(String, bool)> {
+            ModuleReaderImpl::list(&repo, &url(uri)).unwrap().into_iter().map(|e| (e.name, e.is_directory)).collect()
+        };
+        assert_eq!(names("git:/"), [("config".to_string(), true)]);
+        assert_eq!(names("git:/config/"), [("app.pkl".to_string(), false), ("db.pkl".to_string(), false)]);
+        assert_eq!(names(&format!("git:/config/?rev={first}")), [("app.pkl".to_string(), false)]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
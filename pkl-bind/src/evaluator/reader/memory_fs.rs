@@ -0,0 +1,176 @@
+use std::{collections::BTreeMap, io, sync::{Arc, RwLock}};
+
+use url::Url;
+
+use crate::evaluator::msg_api::outgoing::PathElement;
+
+use super::{is_directory, list_children, normalize, ModuleReaderImpl, ResourceReaderImpl};
+
+/// Scheme used by [`MemoryFs::default`], allowed by the default resource allowlist
+pub const DEFAULT_SCHEME: &str = "customfs";
+
+/// An in-memory file tree served as both modules and resources
+///
+/// Paths are hierarchical, `customfs:/config/app.pkl` reads the file inserted
+/// as `config/app.pkl`. Directories exist implicitly for every file below them,
+/// so globbed imports and reads list them too. Clones share the same files,
+/// which can still be changed after the reader was registered.
+///
+/// # Example
+///
+/// ```
+/// use pkl_bind::evaluator::{evaluator_options::EvaluatorOptions, reader::memory_fs::MemoryFs};
+///
+/// let fs = MemoryFs::default()
+///     .with_file("config/app.pkl", "port = read(\"customfs:/config/port.txt\").text.toInt()")
+///     .with_file("config/port.txt", "8080");
+///
+/// let opts = EvaluatorOptions::hermetic()
+///     .with_module_reader(fs.clone())
+///     .with_resource_reader(fs);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemoryFs {
+    scheme: String,
+    files: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCHEME)
+    }
+}
+
+impl MemoryFs {
+    /// An empty file tree served under `scheme`
+    pub fn new(scheme: impl Into<String>) -> Self {
+        Self { scheme: scheme.into(), files: Default::default() }
+    }
+
+    /// Add the file at `path`, see [`MemoryFs::insert`]
+    pub fn with_file(self, path: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.insert(path, contents);
+        self
+    }
+
+    /// Add or replace the file at `path`, which is relative to the root of the tree
+    pub fn insert(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.files.write().expect("memory fs lock poisoned").insert(normalize(path), contents.into());
+    }
+
+    /// Remove the file at `path`, returning its contents
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.write().expect("memory fs lock poisoned").remove(&normalize(path))
+    }
+
+    fn read_bytes(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        let path = normalize(uri.path());
+        let files = self.files.read().expect("memory fs lock poisoned");
+
+        if let Some(contents) = files.get(&path) {
+            return Ok(contents.clone());
+        }
+        if is_directory(files.keys().map(String::as_str), &path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{uri} is a directory")));
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{uri} not found")))
+    }
+
+    fn list_elements(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        let files = self.files.read().expect("memory fs lock poisoned");
+        Ok(list_children(files.keys().map(String::as_str), uri.path()))
+    }
+}
+
+impl ModuleReaderImpl for MemoryFs {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<String> {
+        String::from_utf8(self.read_bytes(uri)?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{uri} is not valid UTF-8")))
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        self.list_elements(uri)
+    }
+}
+
+impl ResourceReaderImpl for MemoryFs {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn has_hierarchical_uris(&self) -> bool {
+        true
+    }
+
+    fn is_globbable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        self.read_bytes(uri)
+    }
+
+    fn list(&self, uri: &Url) -> io::Result<Vec<PathElement>> {
+        self.list_elements(uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(uri: &str) -> Url {
+        Url::parse(uri).unwrap()
+    }
+
+    fn names(elements: Vec<PathElement>) -> Vec<(String, bool)> {
+        elements.into_iter().map(|e| (e.name, e.is_directory)).collect()
+    }
+
+    #[test]
+    fn test_memory_fs() {
+        let fs = MemoryFs::default()
+            .with_file("a.pkl", "a = 1")
+            .with_file("/dir/b.pkl", "b = 2")
+            .with_file("dir/nested/c.txt", vec![0xFF]);
+
+        assert_eq!(ModuleReaderImpl::read(&fs, &url("customfs:/a.pkl")).unwrap(), "a = 1");
+        assert_eq!(ModuleReaderImpl::read(&fs, &url("customfs:///dir/./b.pkl")).unwrap(), "b = 2");
+        assert_eq!(ResourceReaderImpl::read(&fs, &url("customfs:/dir/nested/c.txt")).unwrap(), [0xFF]);
+
+        let err = ModuleReaderImpl::read(&fs, &url("customfs:/dir/nested/c.txt")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = ResourceReaderImpl::read(&fs, &url("customfs:/dir")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = ResourceReaderImpl::read(&fs, &url("customfs:/missing.pkl")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let root = names(ModuleReaderImpl::list(&fs, &url("customfs:/")).unwrap());
+        assert_eq!(root, [("a.pkl".to_string(), false), ("dir".to_string(), true)]);
+        let dir = names(ResourceReaderImpl::list(&fs, &url("customfs:/dir/")).unwrap());
+        assert_eq!(dir, [("b.pkl".to_string(), false), ("nested".to_string(), true)]);
+
+        // clones share their files
+        let clone = fs.clone();
+        clone.insert("dir/d.pkl", "d = 4");
+        assert_eq!(ModuleReaderImpl::read(&fs, &url("customfs:/dir/d.pkl")).unwrap(), "d = 4");
+        assert!(fs.remove("dir/d.pkl").is_some());
+        assert!(ResourceReaderImpl::read(&clone, &url("customfs:/dir/d.pkl")).is_err());
+    }
+}
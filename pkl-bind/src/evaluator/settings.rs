@@ -0,0 +1,98 @@
+//! Reading `pkl:EvaluatorSettings` from `settings.pkl` and `PklProject` files
+
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::Error;
+
+use super::evaluator_options::EvaluatorOptions;
+
+/// Expression turning the settings object bound to `s` into a `Map` of plain values
+const SETTINGS_MAP: &str = r#"Map(
+  "env", s.getPropertyOrNull("env")?.toMap(),
+  "externalProperties", s.getPropertyOrNull("externalProperties")?.toMap(),
+  "allowedModules", s.getPropertyOrNull("allowedModules")?.toList(),
+  "allowedResources", s.getPropertyOrNull("allowedResources")?.toList(),
+  "modulePath", s.getPropertyOrNull("modulePath")?.toList(),
+  "noCache", s.getPropertyOrNull("noCache"),
+  "moduleCacheDir", s.getPropertyOrNull("moduleCacheDir"),
+  "rootDir", s.getPropertyOrNull("rootDir"),
+  "timeout", s.getPropertyOrNull("timeout")?.toUnit("s")?.value?.ceil?.toInt()
+)"#;
+
+/// Expression reading the settings of a `settings.pkl` file
+///
+/// The settings are either the module itself or its `evaluatorSettings`.
+pub(crate) fn settings_file_expr() -> String {
+    format!(r#"let (s = module.getPropertyOrNull("evaluatorSettings") ?? module) {SETTINGS_MAP}"#)
+}
+
+/// Expression reading the `evaluatorSettings` of a `PklProject` file
+pub(crate) fn project_expr() -> String {
+    format!("let (s = module.evaluatorSettings) {SETTINGS_MAP}")
+}
+
+/// pkl binary encoding of a `Map`
+type PklMap = (u8, HashMap<String, String>);
+/// pkl binary encoding of a `List`
+type PklList = (u8, Vec<String>);
+
+/// The evaluator settings as produced by [`SETTINGS_MAP`], `None` when unset
+#[derive(Debug, Default, Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EvaluatorSettings {
+    pub env: Option<PklMap>,
+    pub external_properties: Option<PklMap>,
+    pub allowed_modules: Option<PklList>,
+    pub allowed_resources: Option<PklList>,
+    pub module_path: Option<PklList>,
+    pub no_cache: Option<bool>,
+    pub module_cache_dir: Option<String>,
+    pub root_dir: Option<String>,
+    pub timeout: Option<i64>,
+}
+
+impl EvaluatorSettings {
+    /// Decode the pkl `Map` produced by the settings expressions
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, Error> {
+        let (_, settings): (u8, EvaluatorSettings) = rmp_serde::from_slice(data)
+            .map_err(|err| Error::type_mismatch("EvaluatorSettings", err))?;
+        Ok(settings)
+    }
+
+    /// Override the options that are set, resolving relative paths against `base_dir`
+    pub(crate) fn apply(self, mut opts: EvaluatorOptions, base_dir: &Path) -> EvaluatorOptions {
+        let resolve = |path: String| base_dir.join(path).to_string_lossy().into_owned();
+
+        if let Some((_, env)) = self.env {
+            opts.env = env;
+        }
+        if let Some((_, properties)) = self.external_properties {
+            opts.properties = properties;
+        }
+        if let Some((_, modules)) = self.allowed_modules {
+            opts.allowed_modules = modules;
+        }
+        if let Some((_, resources)) = self.allowed_resources {
+            opts.allowed_resources = resources;
+        }
+        if let Some((_, paths)) = self.module_path {
+            opts.module_paths = paths.into_iter().map(resolve).collect();
+        }
+        if let Some(dir) = self.module_cache_dir {
+            opts.cache_dir = base_dir.join(dir);
+        }
+        if self.no_cache == Some(true) {
+            opts.cache_dir = Default::default();
+        }
+        if let Some(dir) = self.root_dir {
+            opts.root_dir = resolve(dir);
+        }
+        if let Some(seconds) = self.timeout {
+            opts.timeout_seconds = Some(seconds);
+        }
+        opts
+    }
+}
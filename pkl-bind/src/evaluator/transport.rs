@@ -0,0 +1,222 @@
+use std::{io::{self, Read, Write}, process::{Child, Command, Stdio}, sync::mpsc::{channel, Receiver, Sender}};
+
+#[cfg(unix)]
+use std::{net::Shutdown, os::unix::net::UnixStream, path::Path};
+
+/// A bidirectional byte stream to a pkl server
+///
+/// The [`Executor`](super::executor::Executor) takes both halves once:
+/// the reader is handed to the background reader thread and the writer is
+/// used to send messages. [`Transport::close`] is called when the executor
+/// is dropped.
+pub trait Transport: Send {
+    /// Take the half of the connection messages from the server are read from
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Take the half of the connection messages to the server are written to
+    fn writer(&mut self) -> io::Result<Box<dyn Write + Send>>;
+
+    /// Shut the connection down
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn already_taken(half: &str) -> io::Error {
+    io::Error::other(format!("transport {half} was already taken"))
+}
+
+/// Talks to a `pkl server` child process over its stdin and stdout
+#[derive(Debug)]
+pub struct ProcessTransport {
+    child: Child,
+}
+
+impl ProcessTransport {
+    /// Spawn `command` (e.g. `["pkl", "server"]`) with piped stdin and stdout
+    ///
+    /// The server's stderr is inherited, nothing would drain a pipe and a
+    /// chatty server would block once it filled up.
+    pub fn spawn(command: &[String]) -> io::Result<Self> {
+        let (program, args) = command.split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty pkl command"))?;
+
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        Ok(Self { child })
+    }
+}
+
+impl Transport for ProcessTransport {
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send>> {
+        let out = self.child.stdout.take().ok_or_else(|| already_taken("reader"))?;
+        Ok(Box::new(out))
+    }
+
+    fn writer(&mut self) -> io::Result<Box<dyn Write + Send>> {
+        let input = self.child.stdin.take().ok_or_else(|| already_taken("writer"))?;
+        Ok(Box::new(input))
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.child.kill()?;
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// One end of an in-memory connection, see [`MemoryTransport::duplex`]
+///
+/// Useful to drive the protocol from tests without a pkl binary.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    reader: Option<MemoryReader>,
+    writer: Option<MemoryWriter>,
+}
+
+impl MemoryTransport {
+    /// Create two connected ends, bytes written to one are read from the other
+    pub fn duplex() -> (MemoryTransport, MemoryTransport) {
+        let (a_send, a_recv) = channel();
+        let (b_send, b_recv) = channel();
+
+        let a = MemoryTransport {
+            reader: Some(MemoryReader::new(b_recv)),
+            writer: Some(MemoryWriter { sender: a_send }),
+        };
+        let b = MemoryTransport {
+            reader: Some(MemoryReader::new(a_recv)),
+            writer: Some(MemoryWriter { sender: b_send }),
+        };
+
+        (a, b)
+    }
+
+    /// Split into the raw reading and writing halves
+    pub fn split(mut self) -> (MemoryReader, MemoryWriter) {
+        (self.reader.take().expect("halves are only taken once"), self.writer.take().expect("halves are only taken once"))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send>> {
+        let reader = self.reader.take().ok_or_else(|| already_taken("reader"))?;
+        Ok(Box::new(reader))
+    }
+
+    fn writer(&mut self) -> io::Result<Box<dyn Write + Send>> {
+        let writer = self.writer.take().ok_or_else(|| already_taken("writer"))?;
+        Ok(Box::new(writer))
+    }
+}
+
+/// Reading half of a [`MemoryTransport`], reports EOF once the peer's writer is dropped
+#[derive(Debug)]
+pub struct MemoryReader {
+    receiver: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl MemoryReader {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self { receiver, buf: Vec::new(), pos: 0 }
+    }
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Writing half of a [`MemoryTransport`]
+#[derive(Debug)]
+pub struct MemoryWriter {
+    sender: Sender<Vec<u8>>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Talks to an already running pkl server over a Unix domain socket
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocketTransport {
+    stream: UnixStream,
+}
+
+#[cfg(unix)]
+impl UnixSocketTransport {
+    /// Connect to the socket at `path`
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { stream: UnixStream::connect(path)? })
+    }
+
+    /// Use an already connected stream
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixSocketTransport {
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.stream.try_clone()?))
+    }
+
+    fn writer(&mut self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.stream.try_clone()?))
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_duplex() {
+        let (mut a, mut b) = MemoryTransport::duplex();
+
+        let mut a_out = a.writer().unwrap();
+        let mut b_in = b.reader().unwrap();
+        a_out.write_all(b"hello, ").unwrap();
+        a_out.write_all(b"world").unwrap();
+        drop(a_out);
+
+        let mut received = String::new();
+        b_in.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello, world");
+
+        assert!(a.writer().is_err());
+    }
+}
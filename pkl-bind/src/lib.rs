@@ -49,3 +49,13 @@
 
 #![cfg_attr(feature = "unstable", feature(*))]
 pub mod evaluator;
+pub mod error;
+pub mod diagnostic;
+
+pub use error::Error;
+pub use diagnostic::PklDiagnostic;
+#[cfg(feature = "derive")]
+pub use pkl_derive::{embed_pkl, Pkl};
+
+// lets code generated by pkl-derive name `::pkl_bind` inside this crate too
+extern crate self as pkl_bind;
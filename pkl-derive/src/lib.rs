@@ -1,4 +1,6 @@
-use syn::{parse_macro_input, DeriveInput, Data, Fields};
+use std::path::{Path, PathBuf};
+
+use syn::{parse_macro_input, DeriveInput, Data, Fields, LitStr};
 use quote::quote;
 
 #[proc_macro_derive(Pkl)]
@@ -15,9 +17,10 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         use rmp_serde;
 
         impl Pkl for #ident {
-            fn unmarshal(data: Vec<u8>) -> Result<#ident, &'static str> {
+            fn unmarshal(data: Vec<u8>) -> Result<#ident, ::pkl_bind::Error> {
                 let decoded: (i64, ::std::string::String, ::std::string::String, #tuple_types)
-                    = rmp_serde::decode::from_slice(&data).expect("Failed to decode");
+                    = rmp_serde::decode::from_slice(&data)
+                        .map_err(|err| ::pkl_bind::Error::type_mismatch(stringify!(#ident), err))?;
 
                 let values = decoded.3;  // TODO extract the name here to a variable
 
@@ -27,7 +30,7 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         }
     };
 
-    return res.into();
+    res.into()
 }
 
 fn depkl_types(input: &Data) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
@@ -46,13 +49,12 @@ fn depkl_types(input: &Data) -> (proc_macro2::TokenStream, proc_macro2::TokenStr
                         }});
 
                     let types = quote! {
-                        (#(#recursed),*)
+                        (#(#recursed,)*)
                     };
 
                     let mut setters_recursed: Vec<proc_macro2::TokenStream> = vec![];
 
-                    let mut i = 0;
-                    for field in fields.named.iter() {
+                    for (i, field) in fields.named.iter().enumerate() {
                         let ident = &field.ident;
                         let ind = syn::Index::from(i);
                         let setter = quote! {
@@ -60,14 +62,13 @@ fn depkl_types(input: &Data) -> (proc_macro2::TokenStream, proc_macro2::TokenStr
                         };
 
                         setters_recursed.push(setter);
-                        i = i + 1;
                     };
 
                     let setters = quote! {
                         #(#setters_recursed),*
                     };
 
-                    return (types, setters);
+                    (types, setters)
                 }
                 _ => unimplemented!(),
             }
@@ -75,3 +76,64 @@ fn depkl_types(input: &Data) -> (proc_macro2::TokenStream, proc_macro2::TokenStr
         Data::Enum(_) | Data::Union(_) => unimplemented!(),
     }
 }
+
+/// Embed every `.pkl` file below a directory into the binary
+///
+/// The path is relative to the manifest of the calling crate. Expands to a
+/// `pkl_bind::evaluator::reader::embedded::EmbeddedFs` serving the files
+/// under `embedded:`, with paths relative to the directory. The files are
+/// included with `include_str!`, so editing one triggers a rebuild, while
+/// newly added files are only picked up once the calling crate is rebuilt.
+///
+/// Re-exported as `pkl_bind::embed_pkl!` with the `derive` feature.
+///
+/// ```ignore
+/// let schemas = pkl_bind::embed_pkl!("pkl/schemas");
+/// let opts = EvaluatorOptions::hermetic().with_module_reader(schemas);
+/// ```
+#[proc_macro]
+pub fn embed_pkl(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let dir = parse_macro_input!(input as LitStr);
+
+    let Ok(manifest_dir) = std::env::var("CARGO_MANIFEST_DIR") else {
+        let message = "CARGO_MANIFEST_DIR is not set, embed_pkl! must be expanded by cargo";
+        return syn::Error::new(dir.span(), message).to_compile_error().into();
+    };
+    let root = Path::new(&manifest_dir).join(dir.value());
+
+    let mut files = vec![];
+    if let Err(err) = collect_pkl_files(&root, &mut files) {
+        let message = format!("failed to embed {}: {err}", root.display());
+        return syn::Error::new(dir.span(), message).to_compile_error().into();
+    }
+    files.sort();
+
+    let entries = files.iter().map(|file| {
+        let relative = file.strip_prefix(&root).expect("collected below the root");
+        let path = relative.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let absolute = file.to_string_lossy();
+
+        quote! { (#path, include_str!(#absolute)) }
+    });
+
+    let res = quote! {
+        ::pkl_bind::evaluator::reader::embedded::EmbeddedFs::new(&[#(#entries),*])
+    };
+
+    res.into()
+}
+
+fn collect_pkl_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_pkl_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "pkl") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}